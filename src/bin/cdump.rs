@@ -11,6 +11,9 @@ fn main() -> anyhow::Result<()> {
     let args = Args::parse();
     let ix = Index::open(&args.index)?;
     
+    if let Some(tool) = &ix.tool_version {
+        println!("Format {}, written by {}", ix.version, tool);
+    }
     println!("Roots ({}):", ix.num_path);
     let mut r = ix.roots();
     while let Some(p) = r.next() {
@@ -26,8 +29,13 @@ fn main() -> anyhow::Result<()> {
 
     println!("Names ({}):", ix.num_name);
     let mut n = ix.names_at(0, ix.num_name);
+    let mut id = 0;
     while let Some(p) = n.next() {
-        println!("  {}", p);
+        match ix.file_meta(id) {
+            Some(m) => println!("  {} (size={} mtime={} hash={:016x})", p, m.size, m.mtime, m.hash),
+            None => println!("  {}", p),
+        }
+        id += 1;
     }
     
     println!("Postings ({}):", ix.num_post);
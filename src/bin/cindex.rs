@@ -1,46 +1,91 @@
 use clap::Parser;
 use rust_codesearch::index::IndexWriter;
-use rust_codesearch::index::merge::merge;
+use rust_codesearch::index::merge::{fold_delta, is_shadowed, merge, merge_many, merge_retaining, remove_files};
+use rust_codesearch::index::lock::IndexLock;
 use rust_codesearch::index::read::Index;
-use rust_codesearch::find_index_file;
+use rust_codesearch::index::write::{cmp_paths, content_hash, path_within, FileMeta};
+use rust_codesearch::{delta_index_file, find_index_file};
 use ignore::WalkBuilder;
-use std::collections::HashSet;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::collections::BTreeSet;
+use std::path::{Component, Path};
 use std::fs;
-use std::io::{BufRead, BufWriter, Write};
-use std::time::Instant;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use notify::{EventKind, RecursiveMode, Watcher};
+use notify::event::ModifyKind;
 
 /// Checkpoint file stores progress for resumable indexing
 const CHECKPOINT_INTERVAL: usize = 10000; // Save checkpoint every N files
 
+/// With --watch, a batch of changes ends after this long without events,
+/// or once it has been collecting for WATCH_MAX_BATCH
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+const WATCH_MAX_BATCH: Duration = Duration::from_secs(2);
+/// Default seconds between folds of the delta index into the main one
+const FOLD_INTERVAL: u64 = 10;
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[arg(short, long, default_value = "")]
     index: String,
-
+    
     #[arg(short, long)]
     verbose: bool,
-
+    
     #[arg(short = 'n', long, help = "Do not respect .gitignore files")]
     no_ignore: bool,
     
     #[arg(long, help = "Overwrite existing index")]
     reset: bool,
-
+    
     #[arg(short = 'a', long, help = "Index all file types (disable extension filtering)")]
     all_files: bool,
-
+    
     #[arg(short = 'e', long, help = "Additional file extensions to index (comma-separated, e.g., 'rs,go,js')")]
     extensions: Option<String>,
-
+    
     #[arg(long, help = "Checkpoint interval (save progress every N files) [default: 10000]")]
     checkpoint_interval: Option<usize>,
-
+    
     #[arg(long, help = "Resume from checkpoint if available")]
     resume: bool,
-
-    #[arg(required = true)]
+    
+    #[arg(short = 'j', long, help = "Number of threads reading and scanning files [default: number of CPUs]")]
+    threads: Option<usize>,
+    
+    #[arg(long, value_name = "PATH", conflicts_with = "paths", help = "Remove a path and all files under it from the index (repeatable)")]
+    remove: Vec<String>,
+    
+    #[arg(long, value_name = "PATH", conflicts_with = "paths", help = "Remove a root of the index and its files (repeatable)")]
+    drop_root: Vec<String>,
+    
+    #[arg(long, conflicts_with_all = ["paths", "remove", "drop_root", "prune", "merge", "verify", "watch"], help = "List the roots of the index")]
+    list: bool,
+    
+    #[arg(long, conflicts_with = "paths", help = "Remove files that no longer exist from the index")]
+    prune: bool,
+    
+    #[arg(long, value_name = "INDEX", num_args = 1.., conflicts_with_all = ["paths", "remove", "prune"], requires = "output", help = "Merge existing indexes into one; later indexes take precedence")]
+    merge: Vec<String>,
+    
+    #[arg(short = 'o', long, value_name = "FILE", requires = "merge", conflicts_with_all = ["paths", "remove", "drop_root", "prune", "list", "verify", "watch"], help = "Output file for --merge")]
+    output: Option<String>,
+    
+    #[arg(long, help = "Wait for another cindex updating the same index to finish instead of failing")]
+    wait: bool,
+    
+    #[arg(long, conflicts_with_all = ["paths", "remove", "prune", "merge"], help = "Check the index for corruption instead of indexing")]
+    verify: bool,
+    
+    #[arg(long, conflicts_with_all = ["remove", "drop_root", "prune", "merge", "verify"], help = "After indexing, keep watching the paths and index changes as they happen")]
+    watch: bool,
+    
+    #[arg(long, value_name = "SECS", requires = "watch", help = "Fold watched changes into the index at most every SECS seconds; csearch sees them before, csearch serve only once folded [default: 10]")]
+    fold_interval: Option<u64>,
+    
+    #[arg(help = "Paths to index [default: the roots of the index]")]
     paths: Vec<String>,
 }
 
@@ -114,24 +159,21 @@ fn should_index_file(path: &Path, allowed_extensions: &HashSet<String>, index_al
         return true;
     }
     
-    if let Some(extension) = path.extension() {
-        if let Some(ext_str) = extension.to_str() {
-            return allowed_extensions.contains(&ext_str.to_lowercase());
-        }
+    if let Some(extension) = path.extension()
+        && let Some(ext_str) = extension.to_str() {
+        return allowed_extensions.contains(&ext_str.to_lowercase());
     }
     
     // Also index files without extensions (like Makefile, Dockerfile, etc.)
-    if path.extension().is_none() {
-        if let Some(filename) = path.file_name() {
-            if let Some(name_str) = filename.to_str() {
-                let name_lower = name_str.to_lowercase();
-                return matches!(name_lower.as_str(), 
-                    "makefile" | "dockerfile" | "cmakelists.txt" | "readme" | 
-                    "license" | "authors" | "contributors" | "changelog" | 
-                    "news" | "todo" | "install" | "copying" | "notice"
-                );
-            }
-        }
+    if path.extension().is_none()
+        && let Some(filename) = path.file_name()
+        && let Some(name_str) = filename.to_str() {
+        let name_lower = name_str.to_lowercase();
+        return matches!(name_lower.as_str(), 
+            "makefile" | "dockerfile" | "cmakelists.txt" | "readme" | 
+            "license" | "authors" | "contributors" | "changelog" | 
+            "news" | "todo" | "install" | "copying" | "notice"
+        );
     }
     
     false
@@ -142,281 +184,824 @@ fn get_checkpoint_path(index_file: &str) -> String {
     format!("{}.checkpoint", index_file)
 }
 
-/// Get checkpoint index file path (partial index)
-fn get_checkpoint_index_path(index_file: &str) -> String {
-    format!("{}.checkpoint.idx", index_file)
+/// Size and mtime of the files found under the roots that were not indexed
+/// because they are binary, too long or have too many trigrams. They are
+/// kept next to the index so that later runs don't read them again while
+/// they are unchanged.
+fn get_skipped_path(index_file: &str) -> String {
+    format!("{}.skipped", index_file)
 }
 
-/// Load checkpoint - returns set of already indexed files
-fn load_checkpoint(checkpoint_path: &str) -> HashSet<String> {
-    let mut indexed = HashSet::new();
-    if let Ok(file) = fs::File::open(checkpoint_path) {
-        let reader = std::io::BufReader::new(file);
-        for line in reader.lines() {
-            if let Ok(path) = line {
-                if !path.is_empty() {
-                    indexed.insert(path);
-                }
-            }
+/// Reads the skipped files recorded for `index_file`. The record is only a
+/// cache: a missing or damaged one means the files are read again.
+fn read_skipped(index_file: &str) -> HashMap<String, FileMeta> {
+    let mut skipped = HashMap::new();
+    let Ok(data) = fs::read_to_string(get_skipped_path(index_file)) else {
+        return skipped;
+    };
+    for line in data.lines() {
+        let mut fields = line.splitn(3, ' ');
+        if let (Some(size), Some(mtime), Some(name)) = (fields.next(), fields.next(), fields.next())
+            && let (Ok(size), Ok(mtime)) = (size.parse(), mtime.parse()) {
+            skipped.insert(name.to_string(), FileMeta { size, mtime, hash: 0 });
         }
     }
-    indexed
+    skipped
 }
 
-/// Save checkpoint - write all indexed files to checkpoint file
-fn save_checkpoint(checkpoint_path: &str, indexed_files: &[String]) -> anyhow::Result<()> {
-    let file = fs::File::create(checkpoint_path)?;
-    let mut writer = BufWriter::new(file);
-    for path in indexed_files {
-        writeln!(writer, "{}", path)?;
+/// Record the skipped files next to the index, removing the record when
+/// there are none.
+fn write_skipped(index_file: &str, skipped: &HashMap<String, FileMeta>) -> anyhow::Result<()> {
+    let path = get_skipped_path(index_file);
+    if skipped.is_empty() {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => return Ok(()),
+        }
     }
-    writer.flush()?;
+    let mut names: Vec<&String> = skipped.keys().filter(|name| !name.contains('\n')).collect();
+    names.sort_by(|a, b| cmp_paths(a, b));
+    let mut data = String::new();
+    for name in names {
+        let meta = &skipped[name];
+        data.push_str(&format!("{} {} {}\n", meta.size, meta.mtime, name));
+    }
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, &path)?;
     Ok(())
 }
 
 /// Remove checkpoint files after successful completion
 fn cleanup_checkpoint(index_file: &str) {
-    let checkpoint_path = get_checkpoint_path(index_file);
-    let checkpoint_idx_path = get_checkpoint_index_path(index_file);
-    let _ = fs::remove_file(&checkpoint_path);
-    let _ = fs::remove_file(&checkpoint_idx_path);
+    IndexWriter::remove_checkpoint(&get_checkpoint_path(index_file));
 }
 
 /// Checkpoint state for resumable indexing
 struct CheckpointState {
-    indexed_files: Vec<String>,
-    checkpoint_path: String,
+    files_indexed: usize,
     interval: usize,
     last_checkpoint: usize,
     verbose: bool,
 }
 
 impl CheckpointState {
-    fn new(index_file: &str, interval: usize, verbose: bool) -> Self {
+    fn new(interval: usize, verbose: bool) -> Self {
         CheckpointState {
-            indexed_files: Vec::new(),
-            checkpoint_path: get_checkpoint_path(index_file),
+            files_indexed: 0,
             interval,
             last_checkpoint: 0,
             verbose,
         }
     }
     
-    fn add_file(&mut self, path: String) {
-        self.indexed_files.push(path);
+    fn add_file(&mut self) {
+        self.files_indexed += 1;
     }
     
     fn should_checkpoint(&self) -> bool {
-        self.indexed_files.len() - self.last_checkpoint >= self.interval
+        self.files_indexed - self.last_checkpoint >= self.interval
     }
     
-    fn save(&mut self, _ix: &mut IndexWriter) -> anyhow::Result<()> {
-        // Save list of indexed files
-        save_checkpoint(&self.checkpoint_path, &self.indexed_files)?;
+    fn save(&mut self, ix: &mut IndexWriter) -> anyhow::Result<()> {
+        // Persist the names, metadata and posting runs written so far, so a
+        // resumed run continues exactly where this one stopped.
+        ix.checkpoint()?;
         
-        // Note: We only save the file list, not the partial index state.
-        // On resume, files in the checkpoint will be skipped and only new files indexed.
-        // This is simpler and safer than trying to serialize partial index state.
-        
-        self.last_checkpoint = self.indexed_files.len();
+        self.last_checkpoint = self.files_indexed;
         
         if self.verbose {
-            eprintln!("Checkpoint saved: {} files indexed", self.indexed_files.len());
+            eprintln!("Checkpoint saved: {} files indexed", self.files_indexed);
         }
         
         Ok(())
     }
 }
 
-fn index_paths(ix: &mut IndexWriter, paths: &[String], args: &Args, allowed_extensions: &HashSet<String>, index_file: &str) -> anyhow::Result<()> {
-    let checkpoint_interval = args.checkpoint_interval.unwrap_or(CHECKPOINT_INTERVAL);
-    let checkpoint_path = get_checkpoint_path(index_file);
+/// Canonicalize a path, falling back to making it absolute for paths that
+/// do not exist (anymore)
+fn absolute_path(path: &Path) -> String {
+    if let Ok(p) = fs::canonicalize(path) {
+        p.to_string_lossy().to_string()
+    } else if let Ok(p) = std::path::absolute(path) {
+        p.to_string_lossy().to_string()
+    } else {
+        path.to_string_lossy().to_string()
+    }
+}
+
+/// Returns the metadata to keep for `path` if its copy in the old index is
+/// still current. Files whose size and mtime match are not read at all;
+/// files that were only touched are recognized by their content hash.
+fn unchanged_meta(old: &Index, fileid: usize, path: &str) -> Option<FileMeta> {
+    let meta = old.file_meta(fileid)?;
+    let md = fs::metadata(path).ok()?;
+    if meta.same_stat(&md) {
+        return Some(meta);
+    }
+    if meta.size != md.len() {
+        return None;
+    }
+    let data = fs::read(path).ok()?;
+    if content_hash(&data) == meta.hash {
+        Some(FileMeta::new(&md, meta.hash))
+    } else {
+        None
+    }
+}
+
+/// A walk of `path` that skips what indexing skips: hidden files and,
+/// unless disabled, files ignored by `.gitignore` and similar files.
+fn walk_builder(path: &str, args: &Args) -> WalkBuilder {
+    let mut builder = WalkBuilder::new(path);
     
-    // Load existing checkpoint if resuming
-    let already_indexed: HashSet<String> = if args.resume {
-        let indexed = load_checkpoint(&checkpoint_path);
-        if !indexed.is_empty() && args.verbose {
-            eprintln!("Resuming from checkpoint: {} files already indexed", indexed.len());
+    if args.no_ignore {
+        builder.ignore(false);
+        builder.git_ignore(false);
+        builder.git_global(false);
+        builder.git_exclude(false);
+    }
+    builder
+}
+
+/// Add the files found by `builder` that should be indexed to `files`.
+fn collect_files(builder: WalkBuilder, args: &Args, allowed_extensions: &HashSet<String>, files: &mut Vec<String>) -> anyhow::Result<()> {
+    for entry in builder.build() {
+        let entry = entry?;
+        if entry.file_type().is_some_and(|ft| ft.is_file()) {
+            let path = entry.path();
+            
+            if should_index_file(path, allowed_extensions, args.all_files) {
+                 files.push(absolute_path(path));
+            } else if args.verbose {
+                println!("Skipping: {}", path.to_string_lossy());
+            }
         }
-        indexed
-    } else {
-        HashSet::new()
-    };
+    }
+    Ok(())
+}
+
+/// Walk `paths` and add new or modified files to `ix`. When `old` is given,
+/// files it already holds in their current state are recorded in `retain`
+/// instead of being indexed again. Returns the number of files in `ix`,
+/// including any restored from a checkpoint.
+fn index_paths(ix: &mut IndexWriter, paths: &[String], args: &Args, allowed_extensions: &HashSet<String>, old: Option<&Index>, retain: &mut HashMap<usize, FileMeta>, skipped: &mut HashMap<String, FileMeta>) -> anyhow::Result<usize> {
+    let checkpoint_interval = args.checkpoint_interval.unwrap_or(CHECKPOINT_INTERVAL);
+    let threads = args.threads.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    });
+    
+    // Files restored from a checkpoint are already in the writer
+    let mut already_indexed: HashSet<String> = ix.indexed_names()?.into_iter().collect();
+    if !already_indexed.is_empty() && args.verbose {
+        eprintln!("Resuming from checkpoint: {} files already indexed", already_indexed.len());
+    }
     
-    let mut checkpoint_state = CheckpointState::new(index_file, checkpoint_interval, args.verbose);
+    let mut checkpoint_state = CheckpointState::new(checkpoint_interval, args.verbose);
     let start_time = Instant::now();
     let mut files_processed = 0;
-    let mut files_skipped = 0;
+    let mut files_unchanged = 0;
     
+    let mut files = Vec::new();
+    
+    let mut roots = Vec::new();
     for path in paths {
-        let abs_path = if let Ok(p) = fs::canonicalize(path) {
-             p.to_string_lossy().to_string()
-        } else {
-             path.clone()
-        };
-        
-        ix.add_root(&abs_path);
-
-        let mut builder = WalkBuilder::new(path);
-        
-        if args.no_ignore {
-            builder.ignore(false);
-            builder.git_ignore(false);
-            builder.git_global(false);
-            builder.git_exclude(false);
+        let root = absolute_path(Path::new(path));
+        ix.add_root(&root);
+        roots.push(root);
+        collect_files(walk_builder(path, args), args, allowed_extensions, &mut files)?;
+    }
+    
+    // The skipped files under the roots are recorded again as they are
+    // found
+    let (mut known_skipped, others): (HashMap<_, _>, HashMap<_, _>) = std::mem::take(skipped).into_iter()
+        .partition(|(name, _)| is_shadowed(name, &roots));
+    *skipped = others;
+    let mut files_not_indexed = 0;
+    
+    // Names must be added in index path order. Sorting all roots together
+    // keeps that order across roots and drops files found under nested roots twice.
+    files.sort_by(|a, b| cmp_paths(a, b));
+    files.dedup();
+    
+    let total_files = files.len();
+    
+    let mut to_add = Vec::with_capacity(files.len());
+    let mut resumed = Vec::new();
+    for path_str in files {
+        // Skip if already indexed (from checkpoint)
+        if already_indexed.contains(&path_str) {
+            resumed.push(path_str);
+            continue;
         }
         
-        let mut files = Vec::new();
+        if let Some(old) = old
+            && let Some(fileid) = old.find_name(&path_str)
+            && let Some(meta) = unchanged_meta(old, fileid, &path_str) {
+            retain.insert(fileid, meta);
+            files_unchanged += 1;
+            continue;
+        }
         
-        for entry in builder.build() {
-            let entry = entry?;
-            if entry.file_type().map_or(false, |ft| ft.is_file()) {
-                let path = entry.path();
-                
-                if should_index_file(path, allowed_extensions, args.all_files) {
-                     let path_str = if let Ok(p) = fs::canonicalize(path) {
-                         p.to_string_lossy().to_string()
-                     } else {
-                         path.to_string_lossy().to_string()
-                     };
-                     files.push(path_str);
-                } else if args.verbose {
-                    println!("Skipping: {}", path.to_string_lossy());
-                }
-            }
+        if let Some(meta) = known_skipped.remove(&path_str)
+            && fs::metadata(&path_str).is_ok_and(|md| meta.same_stat(&md)) {
+            skipped.insert(path_str, meta);
+            files_not_indexed += 1;
+            continue;
         }
         
-        files.sort();
+        to_add.push(path_str);
+    }
+    
+    // Names must be added after those of the checkpoint, which a file
+    // created since it was saved may not be: start over then
+    if to_add.first().is_some_and(|name| !resumed.is_empty() && cmp_paths(name, ix.last_name()).is_le()) {
+        if args.verbose {
+            eprintln!("Files were added before the checkpoint position, starting over");
+        }
+        ix.restart()?;
+        already_indexed.clear();
+        to_add.append(&mut resumed);
+        to_add.sort_by(|a, b| cmp_paths(a, b));
+    }
+    let files_skipped = resumed.len();
+    
+    ix.add_files(&to_add, threads, |ix, path_str| -> anyhow::Result<()> {
+        if args.verbose {
+            println!("{}", path_str);
+        }
         
-        let total_files = files.len();
+        checkpoint_state.add_file();
+        files_processed += 1;
         
-        for path_str in files {
-            // Skip if already indexed (from checkpoint)
-            if already_indexed.contains(&path_str) {
-                files_skipped += 1;
-                continue;
-            }
+        // Save checkpoint periodically
+        if checkpoint_state.should_checkpoint() {
+            checkpoint_state.save(ix)?;
             
             if args.verbose {
-                println!("{}", path_str);
-            }
-            ix.add_file(&path_str)?;
-            
-            checkpoint_state.add_file(path_str);
-            files_processed += 1;
-            
-            // Save checkpoint periodically
-            if checkpoint_state.should_checkpoint() {
-                checkpoint_state.save(ix)?;
-                
-                if args.verbose {
-                    let elapsed = start_time.elapsed().as_secs_f64();
-                    let rate = files_processed as f64 / elapsed;
-                    eprintln!("Progress: {}/{} files ({:.1} files/sec)", 
-                             files_processed + files_skipped, total_files, rate);
-                }
+                let elapsed = start_time.elapsed().as_secs_f64();
+                let rate = files_processed as f64 / elapsed;
+                eprintln!("Progress: {}/{} files ({:.1} files/sec)", 
+                         files_processed + files_skipped + files_unchanged, total_files, rate);
             }
         }
+        Ok(())
+    })?;
+    
+    // Files that were read but not indexed don't change the index
+    let files_written = ix.num_files();
+    files_not_indexed += ix.skipped_files().len();
+    for (name, meta) in ix.skipped_files() {
+        skipped.insert(name.clone(), *meta);
     }
     
     ix.flush()?;
     
-    // Cleanup checkpoint on successful completion
-    cleanup_checkpoint(index_file);
-    
     if args.verbose {
         let elapsed = start_time.elapsed().as_secs_f64();
-        eprintln!("Indexing complete: {} files indexed, {} skipped (resumed), {:.1}s", 
-                 files_processed, files_skipped, elapsed);
+        eprintln!("Indexing complete: {} files indexed, {} unchanged, {} not indexed (binary or too large), {} skipped (resumed), {:.1}s", 
+                 files_written - already_indexed.len(), files_unchanged, files_not_indexed, files_skipped, elapsed);
+    }
+    
+    Ok(files_written)
+}
+
+/// Reports whether an incremental run found nothing to update: no file was
+/// added, every old file under the walked roots was retained with unchanged
+/// metadata and the roots themselves are already recorded.
+fn is_up_to_date(old: &Index, paths: &[String], files_added: usize, retain: &HashMap<usize, FileMeta>) -> bool {
+    if files_added > 0 {
+        return false;
+    }
+    if !retain.iter().all(|(&id, meta)| old.file_meta(id) == Some(*meta)) {
+        return false;
+    }
+    let new_roots: Vec<String> = paths.iter().map(|p| absolute_path(Path::new(p))).collect();
+    let mut old_roots = HashSet::new();
+    let mut r = old.roots();
+    while let Some(root) = r.next() {
+        old_roots.insert(root);
+    }
+    if !new_roots.iter().all(|root| old_roots.contains(root)) {
+        return false;
+    }
+    
+    let mut covered = 0;
+    let mut n = old.names_at(0, old.num_name);
+    while let Some(name) = n.next() {
+        if is_shadowed(&name, &new_roots) {
+            covered += 1;
+        }
+    }
+    covered == retain.len()
+}
+
+/// Drop files from the index without indexing anything: everything under
+/// the `--remove` paths and, with `--prune`, files and roots that no longer
+/// exist. The index is rewritten without their file ids.
+fn remove_from_index(index_file: &str, args: &Args) -> anyhow::Result<()> {
+    let ix = Index::open(index_file)?;
+    let old_roots = read_roots(&ix);
+    
+    let removed_paths: Vec<String> = args.remove.iter().map(|p| absolute_path(Path::new(p))).collect();
+    let mut dropped = Vec::new();
+    for path in &args.drop_root {
+        let root = absolute_path(Path::new(path));
+        if !old_roots.contains(&root) {
+            anyhow::bail!("{} is not a root of {} (see cindex --list)", root, index_file);
+        }
+        dropped.push(root);
     }
+    let is_gone = |name: &str| {
+        is_shadowed(name, &removed_paths) || (args.prune && !Path::new(name).exists())
+    };
     
+    // Only the roots given are dropped, not those nested in them, and files
+    // that another root still covers are kept
+    let roots: Vec<String> = old_roots.into_iter()
+        .filter(|root| !is_gone(root) && !dropped.contains(root))
+        .collect();
+    let is_removed = |name: &str| {
+        is_gone(name) || (is_shadowed(name, &dropped) && !is_shadowed(name, &roots))
+    };
+    let roots_removed = ix.num_path - roots.len();
+    drop(ix);
+    
+    let files_removed = remove_files(index_file, index_file, &roots, &is_removed)?;
+    let mut skipped = read_skipped(index_file);
+    if !skipped.is_empty() {
+        skipped.retain(|name, _| !is_removed(name));
+        write_skipped(index_file, &skipped)?;
+    }
+    if args.verbose {
+        println!("Removed {} files and {} roots from {}", files_removed, roots_removed, index_file);
+    }
     Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    env_logger::init();
-    let args = Args::parse();
+fn read_roots(ix: &Index) -> Vec<String> {
+    let mut roots = Vec::new();
+    let mut r = ix.roots();
+    while let Some(root) = r.next() {
+        roots.push(root);
+    }
+    roots
+}
 
-    let mut allowed_extensions = get_default_extensions();
-    if let Some(ref ext_list) = args.extensions {
-        for ext in ext_list.split(',') {
-            let ext = ext.trim().to_lowercase();
-            if !ext.is_empty() {
-                allowed_extensions.insert(ext);
+/// Print the roots of the index, with the number of files under each when
+/// verbose.
+fn list_roots(index_file: &str, args: &Args) -> anyhow::Result<()> {
+    let ix = Index::open(index_file)
+        .map_err(|e| anyhow::anyhow!("{}: cannot open index: {}", index_file, e))?;
+    for root in read_roots(&ix) {
+        if args.verbose {
+            println!("{}\t{} files", root, ix.name_range(&root).len());
+        } else {
+            println!("{}", root);
+        }
+    }
+    Ok(())
+}
+
+/// The roots to re-index when cindex is given no paths. Roots that no
+/// longer exist are left as they are.
+fn stored_roots(index_file: &str) -> anyhow::Result<Vec<String>> {
+    let ix = Index::open(index_file)
+        .map_err(|e| anyhow::anyhow!("no paths given and cannot open index {}: {}", index_file, e))?;
+    let mut roots = Vec::new();
+    for root in read_roots(&ix) {
+        if Path::new(&root).exists() {
+            roots.push(root);
+        } else {
+            eprintln!("Skipping root that no longer exists: {} (remove it with --prune or --drop-root)", root);
+        }
+    }
+    if roots.is_empty() {
+        anyhow::bail!("no paths given and {} has no roots to re-index", index_file);
+    }
+    Ok(roots)
+}
+
+/// Take the writer lock of the index, so that concurrent runs don't
+/// overwrite each other's changes.
+fn lock_index(index_file: &str, args: &Args) -> anyhow::Result<IndexLock> {
+    match IndexLock::acquire(index_file, false) {
+        Ok(lock) => Ok(lock),
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock && args.wait => {
+            if args.verbose {
+                println!("Waiting for another cindex to finish with {}", index_file);
             }
+            Ok(IndexLock::acquire(index_file, true)?)
         }
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+            anyhow::bail!("another cindex is updating {} (use --wait to wait for it)", index_file)
+        }
+        Err(e) => anyhow::bail!("cannot lock {}: {}", index_file, e),
     }
-    
-    if args.verbose && !args.all_files {
-        println!("Indexing files with extensions: {:?}", 
-                 allowed_extensions.iter().collect::<Vec<_>>());
+}
+
+/// Decode the whole index and check the section checksums, reporting each
+/// corrupt section.
+fn verify_index(index_file: &str, args: &Args) -> anyhow::Result<()> {
+    let ix = Index::open(index_file)
+        .map_err(|e| anyhow::anyhow!("{}: cannot open index: {}", index_file, e))?;
+    if args.verbose {
+        let tool = ix.tool_version.as_deref().unwrap_or("unknown tool");
+        println!("{}: format {}, written by {}, {} roots, {} files, {} trigrams", index_file, ix.version, tool, ix.num_path, ix.num_name, ix.num_post);
+        if ix.checksums.is_none() {
+            println!("{}: format has no checksums, only decoding the sections", index_file);
+        }
+    }
+    let problems = ix.verify();
+    if problems.is_empty() {
+        println!("{}: ok", index_file);
+        return Ok(());
+    }
+    for p in &problems {
+        eprintln!("{}: {}", index_file, p);
     }
+    anyhow::bail!("{}: {} corrupt sections", index_file, problems.len())
+}
 
-    let index_file = if args.index.is_empty() {
-        find_index_file(true)?
-    } else {
-        args.index.clone()
+/// Combine the `--merge` indexes into the `--output` file in one pass.
+fn merge_indexes(args: &Args) -> anyhow::Result<()> {
+    let output = args.output.as_deref().unwrap_or_default();
+    let srcs: Vec<&str> = args.merge.iter().map(String::as_str).collect();
+    let _lock = lock_index(output, args)?;
+    let report = merge_many(output, &srcs)?;
+    if args.verbose {
+        for root in &report.superseded_roots {
+            println!("Root superseded: {}", root);
+        }
+        let ix = Index::open(output)?;
+        println!("Merged {} indexes into {}: {} files, {} roots", srcs.len(), output, ix.num_name, ix.num_path);
+    }
+    Ok(())
+}
+
+/// The index of the batch of watched changes being added to the delta index
+fn batch_path(index_file: &str) -> String {
+    format!("{}.batch", index_file)
+}
+
+/// The paths an event changed that may matter to the index: files and
+/// directories that were created, removed or renamed, and files that were
+/// modified. A changed ignore file stands for its whole directory.
+fn changed_paths(event: &notify::Event, roots: &[String], index_file: &str) -> Vec<String> {
+    let index_path = Path::new(index_file);
+    let index_name = index_path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let mut changed = Vec::new();
+    // Reading the files to index them raises access events
+    if matches!(event.kind, EventKind::Access(_)) {
+        return changed;
+    }
+    for path in &event.paths {
+        if matches!(event.kind, EventKind::Modify(kind) if !matches!(kind, ModifyKind::Name(_))) && path.is_dir() {
+            continue;
+        }
+        let path = match path.file_name().and_then(|n| n.to_str()) {
+            Some(".gitignore" | ".ignore") => path.parent().unwrap_or(path),
+            _ => path.as_path(),
+        };
+        // The index and its temporary files change with every batch
+        if path.parent() == index_path.parent()
+            && path.file_name().is_some_and(|n| {
+                let n = n.to_string_lossy();
+                n.starts_with(&index_name) || n.starts_with(&format!(".{}", index_name))
+            }) {
+            continue;
+        }
+        let name = path.to_string_lossy().to_string();
+        let Some(root) = roots.iter().find(|root| path_within(&name, root)) else {
+            continue;
+        };
+        // Hidden files are never indexed
+        let hidden = Path::new(&name[root.len()..]).components()
+            .any(|c| matches!(c, Component::Normal(n) if n.to_string_lossy().starts_with('.')));
+        if !hidden {
+            changed.push(name);
+        }
+    }
+    changed
+}
+
+/// Wait up to `wait`, or for ever, for changes and collect them until
+/// they stop coming. Returns no paths if nothing relevant changed.
+fn collect_batch(events: &mpsc::Receiver<notify::Result<notify::Event>>, wait: Option<Duration>, roots: &[String], index_file: &str) -> anyhow::Result<BTreeSet<String>> {
+    let mut changed = BTreeSet::new();
+    let first = match wait {
+        Some(wait) => match events.recv_timeout(wait) {
+            Ok(event) => event,
+            Err(mpsc::RecvTimeoutError::Timeout) => return Ok(changed),
+            Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("file watcher stopped"),
+        },
+        None => events.recv().map_err(|_| anyhow::anyhow!("file watcher stopped"))?,
     };
+    let started = Instant::now();
+    let mut next = Some(first);
+    while let Some(event) = next.take() {
+        match event {
+            // Events were lost, so anything may have changed
+            Ok(event) if event.need_rescan() => changed.extend(roots.iter().cloned()),
+            Ok(event) => changed.extend(changed_paths(&event, roots, index_file)),
+            Err(e) => eprintln!("Watch error: {}", e),
+        }
+        if started.elapsed() < WATCH_MAX_BATCH {
+            match events.recv_timeout(WATCH_DEBOUNCE) {
+                Ok(event) => next = Some(event),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("file watcher stopped"),
+            }
+        }
+    }
+    Ok(changed)
+}
+
+/// Index the current state of the `changed` paths into the delta index.
+/// The batch index gets the changed paths as roots, so that merging it
+/// replaces or drops everything the delta or the main index held under
+/// them. Files are found with the same walk as a full run, pruned to the
+/// changed paths, so the same ignore and extension rules apply.
+fn index_batch(changed: &BTreeSet<String>, roots: &[String], index_file: &str, args: &Args, allowed_extensions: &HashSet<String>) -> anyhow::Result<()> {
+    let mut files = Vec::new();
+    for root in roots.iter().filter(|root| changed.iter().any(|c| path_within(c, root))) {
+        let mut builder = walk_builder(root, args);
+        let wanted: Vec<String> = changed.iter().filter(|c| path_within(c, root)).cloned().collect();
+        builder.filter_entry(move |entry| {
+            let path = entry.path().to_string_lossy();
+            wanted.iter().any(|c| path_within(c, &path) || path_within(&path, c))
+        });
+        collect_files(builder, args, allowed_extensions, &mut files)?;
+    }
+    files.sort_by(|a, b| cmp_paths(a, b));
+    files.dedup();
     
-    let path_exists = Path::new(&index_file).exists();
+    let batch_file = batch_path(index_file);
+    let mut ix = IndexWriter::create(&batch_file)?;
+    ix.log_skip = args.verbose;
+    for path in changed {
+        ix.add_root(path);
+    }
+    let threads = args.threads.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    });
+    ix.add_files(&files, threads, |_, path_str| -> anyhow::Result<()> {
+        if args.verbose {
+            println!("{}", path_str);
+        }
+        Ok(())
+    })?;
+    ix.flush()?;
+    drop(ix);
+    
+    let delta_file = delta_index_file(index_file);
+    if Path::new(&delta_file).exists() {
+        let merged = merge(&delta_file, &delta_file, &batch_file);
+        let _ = fs::remove_file(&batch_file);
+        merged?;
+    } else {
+        fs::rename(&batch_file, &delta_file)?;
+    }
+    Ok(())
+}
+
+/// Fold the delta index into the index and remove it.
+fn fold_changes(index_file: &str, args: &Args) -> anyhow::Result<()> {
+    let delta_file = delta_index_file(index_file);
+    fold_delta(index_file, index_file, &delta_file)?;
+    fs::remove_file(&delta_file)?;
+    if args.verbose {
+        let ix = Index::open(index_file)?;
+        println!("Folded changes into {}: {} files", index_file, ix.num_name);
+    }
+    Ok(())
+}
+
+/// Keep the index live: watch the roots for changes, index each batch of
+/// changes into a delta index and fold that into the index every
+/// `--fold-interval` seconds. Runs until the process is stopped.
+fn watch_index(index_file: &str, args: &Args, allowed_extensions: &HashSet<String>) -> anyhow::Result<()> {
+    let roots: Vec<String> = args.paths.iter().map(|p| absolute_path(Path::new(p))).collect();
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for root in &roots {
+        watcher.watch(Path::new(root), RecursiveMode::Recursive)?;
+    }
+    println!("Watching {} for changes", roots.join(", "));
+    
+    let fold_interval = Duration::from_secs(args.fold_interval.unwrap_or(FOLD_INTERVAL));
+    // When the oldest change not yet folded was indexed
+    let mut unfolded_since: Option<Instant> = None;
+    loop {
+        let wait = unfolded_since.map(|since| fold_interval.saturating_sub(since.elapsed()));
+        let changed = collect_batch(&rx, wait, &roots, index_file)?;
+        if !changed.is_empty() {
+            if args.verbose {
+                println!("Indexing {} changed paths", changed.len());
+            }
+            index_batch(&changed, &roots, index_file, args, allowed_extensions)?;
+            unfolded_since.get_or_insert_with(Instant::now);
+        }
+        if unfolded_since.is_some_and(|since| since.elapsed() >= fold_interval) {
+            fold_changes(index_file, args)?;
+            unfolded_since = None;
+        }
+    }
+}
+
+/// Index `args.paths` into `index_file`, creating the index or updating
+/// the files that changed since the last run.
+fn update_index(index_file: &str, args: &Args, allowed_extensions: &HashSet<String>) -> anyhow::Result<()> {
+    let path_exists = Path::new(index_file).exists();
     
     // Check if existing index is valid
-    let index_valid = if path_exists {
-        Index::open(&index_file).is_ok()
+    let existing = if path_exists {
+        Index::open(index_file).ok()
     } else {
-        false
+        None
     };
+    let index_valid = existing.is_some();
+    
+    // Indexes from before format 3 have neither file metadata nor names in
+    // index path order, so they cannot be merged into. Rebuild them from
+    // their roots instead.
+    let mut paths = args.paths.clone();
+    let outdated = existing.as_ref().is_some_and(|ix| ix.version < 3);
+    if let Some(ix) = existing.as_ref().filter(|_| outdated && !args.reset) {
+        if args.verbose {
+            println!("Index uses an older format, rebuilding it from its roots");
+        }
+        for root in read_roots(ix) {
+            if !paths.contains(&root) {
+                paths.push(root);
+            }
+        }
+    }
+    drop(existing);
     
-    // Check for existing checkpoint
-    let checkpoint_path = get_checkpoint_path(&index_file);
-    let has_checkpoint = Path::new(&checkpoint_path).exists();
+    let checkpoint_path = get_checkpoint_path(index_file);
+    let temp_new = format!("{}.tmp_new", index_file);
+    
+    // The checkpoint records which file the interrupted run was writing:
+    // the index itself when creating it, or the new part when updating.
+    let mut resumed = None;
+    if args.resume && Path::new(&checkpoint_path).exists() {
+        match IndexWriter::resume(&checkpoint_path) {
+            Ok(ix) if ix.file() == index_file || (ix.file() == temp_new && index_valid) => {
+                if args.verbose {
+                    println!("Found checkpoint, will resume indexing");
+                }
+                resumed = Some(ix);
+            }
+            Ok(_) => {
+                if args.verbose {
+                    println!("Checkpoint does not match the index, starting over");
+                }
+                cleanup_checkpoint(index_file);
+            }
+            Err(e) => {
+                eprintln!("Ignoring unusable checkpoint {}: {}", checkpoint_path, e);
+                cleanup_checkpoint(index_file);
+            }
+        }
+    }
     
     // Decide whether to create new index or update existing
-    // - Create new if: reset flag, no index exists, index is invalid, or resuming with checkpoint
-    let should_create_new = args.reset || !path_exists || !index_valid || (args.resume && has_checkpoint);
+    // - Create new if: reset flag, no index exists or index is invalid
+    // - A resumed run keeps the mode it was started in
+    // Whether a file is skipped depends only on its contents, so the
+    // record survives rebuilding the index, but not --reset
+    let mut skipped = if args.reset { HashMap::new() } else { read_skipped(index_file) };
+    
+    let should_create_new = match &resumed {
+        Some(ix) => ix.file() == index_file,
+        None => args.reset || !path_exists || !index_valid || outdated,
+    };
     
     if should_create_new {
-        if args.resume && has_checkpoint && args.verbose {
-            println!("Found checkpoint, will resume indexing");
-        }
-        
-        if args.verbose {
-            if !index_valid && path_exists && !has_checkpoint {
+        if args.verbose && resumed.is_none() {
+            if !index_valid && path_exists {
                 println!("Existing index is invalid, overwriting: {}", index_file);
-            } else if !args.resume || !has_checkpoint {
+            } else {
                 println!("Creating index at: {}", index_file);
             }
         }
-        let mut ix = IndexWriter::create(&index_file)?;
+        let mut ix = match resumed {
+            Some(ix) => ix,
+            None => IndexWriter::create_checkpointed(index_file, &checkpoint_path)?,
+        };
         ix.verbose = args.verbose;
         ix.log_skip = args.verbose;
-        index_paths(&mut ix, &args.paths, &args, &allowed_extensions, &index_file)?;
+        index_paths(&mut ix, &paths, args, allowed_extensions, None, &mut HashMap::new(), &mut skipped)?;
+        // Cleanup checkpoint on successful completion
+        cleanup_checkpoint(index_file);
     } else {
         if args.verbose { println!("Updating index at: {}", index_file); }
         
-        let temp_new = format!("{}.tmp_new", index_file);
-        let temp_merged = format!("{}.tmp_merged", index_file);
+        // Only files that changed since the last run are re-read; the
+        // postings of the others are carried over by the merge.
+        let old = Index::open(index_file)?;
+        let mut retain = HashMap::new();
         
-        let mut ix = IndexWriter::create(&temp_new)?;
+        let mut ix = match resumed {
+            Some(ix) => ix,
+            None => IndexWriter::create_checkpointed(&temp_new, &checkpoint_path)?,
+        };
         ix.verbose = args.verbose;
         ix.log_skip = args.verbose;
-        index_paths(&mut ix, &args.paths, &args, &allowed_extensions, &index_file)?;
+        let files_added = index_paths(&mut ix, &args.paths, args, allowed_extensions, Some(&old), &mut retain, &mut skipped)?;
         
-        // Merge
-        match merge(&temp_merged, &index_file, &temp_new) {
-            Ok(_) => {
-                fs::rename(&temp_merged, &index_file)?;
-                let _ = fs::remove_file(&temp_new);
-                // Cleanup checkpoint for the main index file
-                cleanup_checkpoint(&index_file);
-            }
-            Err(e) => {
-                let _ = fs::remove_file(&temp_new);
-                let _ = fs::remove_file(&temp_merged);
-                return Err(e.into());
+        if is_up_to_date(&old, &args.paths, files_added, &retain) {
+            if args.verbose { println!("Index is up to date: {}", index_file); }
+            let _ = fs::remove_file(&temp_new);
+            return write_skipped(index_file, &skipped);
+        }
+        drop(old);
+        
+        // Merge into a new generation of the index, replacing the old one
+        // only once it is complete
+        let merged = merge_retaining(index_file, index_file, &temp_new, &retain);
+        let _ = fs::remove_file(&temp_new);
+        let report = merged?;
+        if args.verbose {
+            for root in &report.superseded_roots {
+                println!("Root superseded: {}", root);
             }
         }
+        // Cleanup checkpoint for the main index file
+        cleanup_checkpoint(index_file);
     }
+    
+    write_skipped(index_file, &skipped)
+}
 
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let mut args = Args::parse();
+    
+    if !args.merge.is_empty() {
+        return merge_indexes(&args);
+    }
+    
+    let index_file = if args.index.is_empty() {
+        find_index_file(true)?
+    } else {
+        args.index.clone()
+    };
+    
+    if args.verify {
+        return verify_index(&index_file, &args);
+    }
+    
+    if args.list {
+        return list_roots(&index_file, &args);
+    }
+    
+    let _lock = lock_index(&index_file, &args)?;
+    
+    // csearch searches the delta index along with the index, so the
+    // changes of a watch that was stopped are folded in before the index
+    // changes under them
+    let _ = fs::remove_file(batch_path(&index_file));
+    let delta_file = delta_index_file(&index_file);
+    if Path::new(&delta_file).exists()
+        && let Err(e) = fold_changes(&index_file, &args) {
+        eprintln!("Discarding {}, which cannot be folded into the index: {}", delta_file, e);
+        let _ = fs::remove_file(&delta_file);
+    }
+    
+    if !args.remove.is_empty() || !args.drop_root.is_empty() || args.prune {
+        return remove_from_index(&index_file, &args);
+    }
+    
+    // Without paths, the roots the index already has are scanned again
+    if args.paths.is_empty() {
+        args.paths = stored_roots(&index_file)?;
+        if args.verbose {
+            println!("Re-indexing roots: {}", args.paths.join(", "));
+        }
+    }
+    
+    let mut allowed_extensions = get_default_extensions();
+    if let Some(ref ext_list) = args.extensions {
+        for ext in ext_list.split(',') {
+            let ext = ext.trim().to_lowercase();
+            if !ext.is_empty() {
+                allowed_extensions.insert(ext);
+            }
+        }
+    }
+    
+    if args.verbose && !args.all_files {
+        println!("Indexing files with extensions: {:?}", 
+                 allowed_extensions.iter().collect::<Vec<_>>());
+    }
+    
+    update_index(&index_file, &args, &allowed_extensions)?;
+    
+    if args.watch {
+        watch_index(&index_file, &args, &allowed_extensions)?;
+    }
+    
     Ok(())
 }
\ No newline at end of file
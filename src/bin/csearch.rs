@@ -1,12 +1,21 @@
 use anyhow::{Context, Result};
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use rust_codesearch::index::{Index, regexp};
-use rust_codesearch::find_index_file;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
-use regex::bytes::RegexBuilder;
-use std::path::Path;
-use ignore::types::TypesBuilder;
+use rust_codesearch::index::merge::is_shadowed;
+use rust_codesearch::index::postings::{AllIter, ListIter, PostingIterator};
+use rust_codesearch::{delta_index_file, find_index_files, find_stacked_index_files};
+use rust_codesearch::server::{Listen, Server};
+use rust_codesearch::search::{self, resolve_path, Match as SearchMatch, SearchOptions, Searcher};
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+use std::io::Write;
+use std::time::{Duration, Instant};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use ignore::types::{Types, TypesBuilder};
 use ignore::Match;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
@@ -31,16 +40,27 @@ enum ColorMode {
 }
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
-    /// The index file to use
+    #[command(subcommand)]
+    command: Option<Command>,
+    
+    
+    /// The index file to use, can be repeated to search several indexes
+    /// [default: found from the current directory, or the indexes in
+    /// CSEARCHINDEX]
     #[arg(short = 'x', long)]
-    index: Option<String>,
-
+    index: Vec<String>,
+    
+    /// Search every .csearchindex from the current directory up. A deeper
+    /// index takes precedence for the files under its roots
+    #[arg(long, conflicts_with = "index")]
+    stack: bool,
+    
     /// Verbose output
     #[arg(short, long)]
     verbose: bool,
-
+    
     /// Case-insensitive search
     #[arg(short = 'i', long)]
     ignore_case: bool,
@@ -48,31 +68,148 @@ struct Args {
     /// Print line number
     #[arg(short = 'n', long)]
     line_number: bool,
-
+    
     /// Filter by file type (e.g. "rust", "cpp", "go")
     #[arg(short = 'f', long = "file-type")]
     file_type: Option<String>,
-
+    
     /// List supported file types
     #[arg(long)]
     list_file_types: bool,
-
+    
     /// The pattern to search for
     pattern: Option<String>,
-
+    
+    /// List indexed files whose path matches this regex. With a pattern,
+    /// search only those files
+    #[arg(long, value_name = "REGEX")]
+    files: Option<String>,
+    
     /// Filter by current working directory
-    #[arg(long)]
+    #[arg(long, conflicts_with = "dir")]
     pwd: bool,
-
+    
+    /// Only search files within this directory
+    #[arg(long, value_name = "PATH")]
+    dir: Option<String>,
+    
     /// Path display format (relative, full, unc)
     #[arg(short = 'p', long, value_enum, default_value = "relative")]
     path_format: PathFormat,
-
+    
+    /// Print NUM lines of context after each match
+    #[arg(short = 'A', long, value_name = "NUM")]
+    after_context: Option<usize>,
+    
+    /// Print NUM lines of context before each match
+    #[arg(short = 'B', long, value_name = "NUM")]
+    before_context: Option<usize>,
+    
+    /// Print NUM lines of context before and after each match
+    #[arg(short = 'C', long, value_name = "NUM")]
+    context: Option<usize>,
+    
+    /// Match the pattern against whole files, so that matches can span
+    /// lines
+    #[arg(short = 'U', long)]
+    multiline: bool,
+    
+    /// Number of threads searching files [default: number of CPUs]
+    #[arg(short = 'j', long)]
+    threads: Option<usize>,
+    
+    /// Show the trigram query of the pattern, the number of files each
+    /// part of it selects and why it selects every file if it does,
+    /// instead of searching
+    #[arg(long, requires = "pattern")]
+    explain: bool,
+    
+    /// Print results as JSON Lines, in the format of ripgrep's --json
+    #[arg(long, requires = "pattern")]
+    json: bool,
+    
+    /// Print only the paths of files with a match
+    #[arg(short = 'l', long, requires = "pattern",
+          conflicts_with_all = ["files_without_match", "count", "only_matching", "json"])]
+    files_with_matches: bool,
+    
+    /// Print only the paths of files without a match
+    #[arg(short = 'L', long, requires = "pattern",
+          conflicts_with_all = ["count", "only_matching", "json"])]
+    files_without_match: bool,
+    
+    /// Print the number of matching lines of each file with a match
+    #[arg(short = 'c', long, requires = "pattern", conflicts_with_all = ["only_matching", "json"])]
+    count: bool,
+    
+    /// Print each match on its own line instead of the matching lines
+    #[arg(short = 'o', long, requires = "pattern", conflicts_with = "json")]
+    only_matching: bool,
+    
+    /// Stop reading a file after NUM matching lines
+    #[arg(short = 'm', long, value_name = "NUM")]
+    max_count: Option<u64>,
+    
+    /// Stop after NUM results in all: matching lines, or files with -l,
+    /// -L and -c
+    #[arg(long, value_name = "NUM")]
+    max_results: Option<u64>,
+    
     /// Color output mode (auto, always, never)
-    #[arg(short = 'c', long, value_enum, default_value = "auto")]
+    #[arg(long, value_enum, default_value = "auto")]
     color: ColorMode,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Keep indexes open and answer search, file list and stats requests
+    /// as JSON over HTTP
+    Serve(ServeArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
+    /// Index file to serve, can be repeated [default: the ones csearch
+    /// would search]
+    #[arg(short = 'x', long = "index")]
+    indexes: Vec<String>,
+    
+    /// Loopback address and port to listen on
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:7878")]
+    listen: SocketAddr,
+    
+    /// Listen on this Unix socket instead of a port
+    #[arg(long, value_name = "PATH")]
+    socket: Option<PathBuf>,
+    
+    /// Number of threads searching files for each request [default: number
+    /// of CPUs]
+    #[arg(short = 'j', long)]
+    threads: Option<usize>,
+}
+
+/// Serve indexes over HTTP until the process is stopped
+fn serve(args: ServeArgs) -> Result<()> {
+    let indexes = if args.indexes.is_empty() {
+        find_index_files()?
+    } else {
+        args.indexes
+    };
+    let mut server = Server::new(&indexes).context("failed to open index")?;
+    if let Some(threads) = args.threads {
+        server.search_threads = threads;
+    }
+    let listen = match args.socket {
+        #[cfg(unix)]
+        Some(path) => Listen::Unix(path),
+        #[cfg(not(unix))]
+        Some(_) => anyhow::bail!("Unix sockets are not supported on this platform"),
+        None => Listen::Tcp(args.listen),
+    };
+    server.run(&listen).context("server failed")?;
+    Ok(())
+}
+
 /// Format path according to the specified format
 fn format_path(path: &Path, format: PathFormat, cwd: &Path) -> String {
     match format {
@@ -113,54 +250,60 @@ fn format_path(path: &Path, format: PathFormat, cwd: &Path) -> String {
 
 /// Strip Windows UNC prefix (\\?\) from path
 fn strip_unc_prefix(path: &str) -> String {
-    if path.starts_with(r"\\?\") {
-        path[4..].to_string()
+    if let Some(stripped) = path.strip_prefix(r"\\?\") {
+        stripped.to_string()
     } else {
         path.to_string()
     }
 }
 
-/// Print a line with highlighted matches
+/// Print a line with highlighted matches. Context lines are separated
+/// from their path and line number by `-` instead of `:`.
 fn print_highlighted_line(
     stdout: &mut StandardStream,
     path: &str,
     line_num: Option<u64>,
-    line: &str,
-    re: &regex::bytes::Regex,
+    m: &SearchMatch,
     use_color: bool,
 ) -> std::io::Result<()> {
+    let sep = if m.context { '-' } else { ':' };
+    // Remove the line terminator
+    let line_bytes = m.line.strip_suffix(b"\n").unwrap_or(&m.line);
+    let line_bytes = line_bytes.strip_suffix(b"\r").unwrap_or(line_bytes);
     if use_color {
         // Print filename in magenta/bold
         stdout.set_color(ColorSpec::new().set_fg(Some(Color::Magenta)).set_bold(true))?;
         write!(stdout, "{}", path)?;
         stdout.reset()?;
         
-        write!(stdout, ":")?;
+        write!(stdout, "{}", sep)?;
         
         // Print line number in green if present
         if let Some(num) = line_num {
             stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
             write!(stdout, "{}", num)?;
             stdout.reset()?;
-            write!(stdout, ":")?;
+            write!(stdout, "{}", sep)?;
         }
         
         // Highlight matching parts in line
-        let line_bytes = line.as_bytes();
         let mut last_end = 0;
         
-        for mat in re.find_iter(line_bytes) {
+        // Matches of the \r alone are not shown
+        for range in m.submatches.iter().filter(|r| r.start < line_bytes.len() || !m.line[line_bytes.len()..].starts_with(b"\r")) {
+            let start = range.start.min(line_bytes.len());
+            let end = range.end.min(line_bytes.len());
             // Print non-matching part
-            let before = String::from_utf8_lossy(&line_bytes[last_end..mat.start()]);
+            let before = String::from_utf8_lossy(&line_bytes[last_end..start]);
             write!(stdout, "{}", before)?;
             
             // Print matching part in red/bold
             stdout.set_color(ColorSpec::new().set_fg(Some(Color::Red)).set_bold(true))?;
-            let matched = String::from_utf8_lossy(&line_bytes[mat.start()..mat.end()]);
+            let matched = String::from_utf8_lossy(&line_bytes[start..end]);
             write!(stdout, "{}", matched)?;
             stdout.reset()?;
             
-            last_end = mat.end();
+            last_end = end;
         }
         
         // Print remaining part
@@ -168,18 +311,501 @@ fn print_highlighted_line(
         writeln!(stdout, "{}", after)?;
     } else {
         // No color - simple output
+        let line = String::from_utf8_lossy(line_bytes);
         if let Some(num) = line_num {
-            writeln!(stdout, "{}:{}:{}", path, num, line)?;
+            writeln!(stdout, "{}{}{}{}{}", path, sep, num, sep, line)?;
         } else {
-            writeln!(stdout, "{}:{}", path, line)?;
+            writeln!(stdout, "{}{}{}", path, sep, line)?;
+        }
+    }
+    
+    Ok(())
+}
+
+/// Print a path on its own line, or followed by `suffix`
+fn print_path(stdout: &mut StandardStream, path: &str, suffix: &str, use_color: bool) -> std::io::Result<()> {
+    if use_color {
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Magenta)).set_bold(true))?;
+        write!(stdout, "{}", path)?;
+        stdout.reset()?;
+        writeln!(stdout, "{}", suffix)
+    } else {
+        writeln!(stdout, "{}{}", path, suffix)
+    }
+}
+
+/// Print each match of a line on its own line, without the line
+/// terminator.
+fn print_only_matching(
+    stdout: &mut StandardStream,
+    path: &str,
+    line_num: Option<u64>,
+    m: &SearchMatch,
+    use_color: bool,
+) -> std::io::Result<()> {
+    let content = m.line.strip_suffix(b"\n").unwrap_or(&m.line);
+    let content = content.strip_suffix(b"\r").unwrap_or(content);
+    for range in &m.submatches {
+        let end = range.end.min(content.len());
+        if range.start >= end {
+            continue;
+        }
+        let part = SearchMatch {
+            line: content[range.start..end].to_vec(),
+            submatches: std::iter::once(0..end - range.start).collect(),
+            ..m.clone()
+        };
+        print_highlighted_line(stdout, path, line_num, &part, use_color)?;
+    }
+    Ok(())
+}
+
+/// Print the separator between groups of context lines
+fn print_separator(stdout: &mut StandardStream, use_color: bool) -> std::io::Result<()> {
+    if use_color {
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Cyan)))?;
+        write!(stdout, "--")?;
+        stdout.reset()?;
+        writeln!(stdout)
+    } else {
+        writeln!(stdout, "--")
+    }
+}
+
+/// A limit on the number of results printed in all.
+struct ResultLimit {
+    max: Option<u64>,
+    count: u64,
+}
+
+impl ResultLimit {
+    fn reached(&self) -> bool {
+        self.max.is_some_and(|max| self.count >= max)
+    }
+    
+    /// Counts one more result, unless the limit is reached.
+    fn take(&mut self) -> bool {
+        if self.reached() {
+            return false;
+        }
+        self.count += 1;
+        true
+    }
+    
+    /// Counts the matching lines of `lines` and returns how many of them to
+    /// print: up to the last match within the limit and the
+    /// `after_context` lines that follow it.
+    fn take_lines(&mut self, lines: &[SearchMatch], after_context: usize) -> usize {
+        if self.reached() {
+            return 0;
+        }
+        let mut trailing = None;
+        for (i, m) in lines.iter().enumerate() {
+            if let Some(left) = trailing {
+                if left == 0 || !m.context {
+                    return i;
+                }
+                trailing = Some(left - 1);
+            } else if !m.context {
+                self.take();
+                if self.reached() {
+                    trailing = Some(after_context);
+                }
+            }
+        }
+        lines.len()
+    }
+}
+
+/// Search statistics of the `end` and `summary` JSON messages.
+#[derive(Default)]
+struct JsonStats {
+    elapsed: Duration,
+    searches: u64,
+    searches_with_match: u64,
+    bytes_searched: u64,
+    bytes_printed: u64,
+    matched_lines: u64,
+    matches: u64,
+}
+
+impl JsonStats {
+    fn add(&mut self, other: &JsonStats) {
+        self.elapsed += other.elapsed;
+        self.searches += other.searches;
+        self.searches_with_match += other.searches_with_match;
+        self.bytes_searched += other.bytes_searched;
+        self.bytes_printed += other.bytes_printed;
+        self.matched_lines += other.matched_lines;
+        self.matches += other.matches;
+    }
+    
+    fn to_json(&self) -> Value {
+        json!({
+            "elapsed": json_duration(self.elapsed),
+            "searches": self.searches,
+            "searches_with_match": self.searches_with_match,
+            "bytes_searched": self.bytes_searched,
+            "bytes_printed": self.bytes_printed,
+            "matched_lines": self.matched_lines,
+            "matches": self.matches,
+        })
+    }
+}
+
+fn json_duration(d: Duration) -> Value {
+    json!({
+        "secs": d.as_secs(),
+        "nanos": d.subsec_nanos(),
+        "human": format!("{:0.6}s", d.as_secs_f64()),
+    })
+}
+
+/// Text as ripgrep encodes it: a string when it is valid UTF-8, and
+/// base64 otherwise.
+fn json_data(bytes: &[u8]) -> Value {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => json!({ "text": text }),
+        Err(_) => json!({ "bytes": BASE64.encode(bytes) }),
+    }
+}
+
+fn json_path(path: &Path, display_path: &str) -> Value {
+    match path.to_str() {
+        Some(_) => json!({ "text": display_path }),
+        None => json!({ "bytes": BASE64.encode(path.as_os_str().as_encoded_bytes()) }),
+    }
+}
+
+/// Writes one JSON message on its own line and returns its size.
+fn write_json(stdout: &mut StandardStream, kind: &str, data: Value) -> std::io::Result<u64> {
+    let line = json!({ "type": kind, "data": data }).to_string();
+    writeln!(stdout, "{}", line)?;
+    Ok(line.len() as u64 + 1)
+}
+
+fn json_match(path: &Value, m: &SearchMatch) -> Value {
+    let submatches: Vec<Value> = m.submatches.iter()
+        .map(|r| json!({ "match": json_data(&m.line[r.clone()]), "start": r.start, "end": r.end }))
+        .collect();
+    json!({
+        "path": path,
+        "lines": json_data(&m.line),
+        "line_number": m.line_number,
+        "absolute_offset": m.byte_offset,
+        "submatches": submatches,
+    })
+}
+
+/// Returns the ids of the indexed files whose path matches `pattern`.
+fn find_files(index: &Index, pattern: &str, ignore_case: bool, verbose: bool) -> Result<Vec<u32>> {
+    if verbose {
+        let pattern = if ignore_case { format!("(?i){}", pattern) } else { pattern.to_string() };
+        if let Ok(q) = regexp::analyze_regexp(&pattern) {
+            eprintln!("file query: {:?}", q);
+        }
+    }
+    let ids = search::find_files(index, pattern, ignore_case).context("invalid file regex")?;
+    if verbose {
+        eprintln!("file regex matches {} files", ids.len());
+    }
+    Ok(ids)
+}
+
+/// Output state shared by the searches of all the indexes, so that their
+/// results form one stream.
+struct Output {
+    stdout: StandardStream,
+    use_color: bool,
+    cwd: PathBuf,
+    limit: ResultLimit,
+    last_line: Option<(PathBuf, u64)>,
+    json_stats: JsonStats,
+    /// With several indexes, the files already searched or listed, which
+    /// later indexes skip
+    seen: Option<HashSet<PathBuf>>,
+    /// With --stack, the roots of the deeper indexes already searched, and
+    /// those of the delta index of the index being searched, whose files
+    /// the index skips
+    covered: Vec<String>,
+}
+
+/// Search one index, or list its files, writing the results to `out`.
+fn search_index(args: &Args, index_path: &str, index: &Index, types_matcher: Option<&Types>, scope_dir: Option<&Path>, out: &mut Output) -> Result<()> {
+    let index_dir = Path::new(index_path).parent().unwrap_or(Path::new("."));
+    
+    if args.verbose {
+        eprintln!("Index info: num_name={}, num_post={}, name_data={}, name_index={}, post_data={}, post_index={}", 
+                  index.num_name, index.num_post, index.name_data, index.name_index, index.post_data, index.post_index);
+    }
+    
+    // Files within a directory have consecutive ids, so scoping the search
+    // to one restricts the candidates to a range
+    let scope = scope_dir.map(|dir| index.name_range(&dir.to_string_lossy()));
+    if args.verbose && let Some(ref scope) = scope {
+        eprintln!("directory scope covers files {}..{}", scope.start, scope.end);
+    }
+    
+    // With --files, the candidates are restricted to the matching names
+    let name_matches = match args.files {
+        Some(ref files) => {
+            let mut ids = find_files(index, files, args.ignore_case, args.verbose)?;
+            if let Some(ref scope) = scope {
+                ids.retain(|&id| scope.contains(&(id as usize)));
+            }
+            Some(ids)
         }
+        None => scope.map(|scope| (scope.start as u32..scope.end as u32).collect()),
+    };
+    
+    // Listing files or counts prints no lines, and only -c and -o need
+    // more than the first match of a file
+    let lists_files = args.files_with_matches || args.files_without_match;
+    let show_lines = !(lists_files || args.count || args.only_matching);
+    let context = |n: Option<usize>| if show_lines { n.or(args.context).unwrap_or(0) } else { 0 };
+    let searcher = match args.pattern {
+        Some(ref pattern) => {
+            let options = SearchOptions {
+                ignore_case: args.ignore_case,
+                base_dir: index_dir.to_path_buf(),
+                before_context: context(args.before_context),
+                after_context: context(args.after_context),
+                multiline: args.multiline,
+                max_count: if lists_files { Some(args.max_count.map_or(1, |n| n.min(1))) } else { args.max_count },
+            };
+            let searcher = Searcher::new(index, pattern, options).context("invalid pattern")?;
+            if args.verbose {
+                eprintln!("pattern: {}", pattern);
+                eprintln!("query: {:?}", searcher.query());
+            }
+            Some(searcher)
+        }
+        None => None,
+    };
+    
+    if args.explain && let Some(ref searcher) = searcher {
+        write!(out.stdout, "{}", searcher.explain(name_matches).context("failed to explain query")?)?;
+        return Ok(());
+    }
+    
+    // -L also prints the files in scope that are not candidates
+    let mut scope_ids: Box<dyn PostingIterator> = match name_matches {
+        Some(ref ids) if args.files_without_match => Box::new(ListIter::new(ids.clone())),
+        _ => Box::new(AllIter::new(index.num_name as u32)),
+    };
+    
+    // Candidates are decoded as the files are searched
+    let mut post: Box<dyn PostingIterator> = match (&searcher, name_matches) {
+        (Some(searcher), name_matches) => searcher.candidates(name_matches),
+        (None, Some(ids)) => Box::new(ListIter::new(ids)),
+        (None, None) => Box::new(AllIter::new(index.num_name as u32)),
+    };
+    
+    if args.verbose {
+        eprintln!("post query estimated at most {} possible files", post.estimate());
+    }
+    
+    let show_context = show_lines && [args.after_context, args.before_context, args.context].iter().any(|n| n.is_some_and(|n| n > 0));
+    let after_context = context(args.after_context);
+    let Output { stdout, use_color, cwd, limit, last_line, json_stats, seen, covered } = out;
+    let use_color = *use_color;
+    let seen = RefCell::new(seen.as_mut());
+    // Whether no earlier index had this file, which it then records
+    let first_sight = |path: &Path| seen.borrow_mut().as_mut().is_none_or(|seen| seen.insert(path.to_path_buf()));
+    let threads = args.threads.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    });
+    // The path of a file, unless the file type filter rules it out
+    let file_path = |fileid: u32| {
+        let name = index.name(fileid as usize);
+        if name.is_empty() {
+            if args.verbose {
+                eprintln!("Warning: empty filename for fileid {}", fileid);
+            }
+            return None;
+        }
+        
+        let path = resolve_path(index_dir, &name);
+        
+        // A deeper index already searched the files under its roots
+        if !covered.is_empty() && is_shadowed(&path.to_string_lossy(), covered) {
+            return None;
+        }
+        
+        if let Some(matcher) = types_matcher
+             && !matches!(matcher.matched(&path, false), Match::Whitelist(_)) {
+             return None;
+        }
+        Some(path)
+    };
+    let mut num_candidates = 0;
+    // The ids of the files handed to the search, in order, which -L needs
+    // to tell the files between them that are not candidates
+    let searched_ids = RefCell::new(VecDeque::new());
+    let mut next_path = || loop {
+        let fileid = post.next()?;
+        num_candidates += 1;
+        if let Some(path) = file_path(fileid)
+            && first_sight(&path) {
+            searched_ids.borrow_mut().push_back(fileid);
+            return Some(path);
+        }
+    };
+    let mut next_scope_id = scope_ids.next();
+    // Prints the files in scope below `end` that are not candidates, which
+    // cannot match, for -L
+    let mut print_non_candidates = |stdout: &mut StandardStream, limit: &mut ResultLimit, end: Option<u32>| -> std::io::Result<()> {
+        while let Some(id) = next_scope_id
+            && end.is_none_or(|end| id < end) {
+            if let Some(path) = file_path(id)
+                && first_sight(&path)
+                && limit.take() {
+                print_path(stdout, &format_path(&path, args.path_format, cwd), "", use_color)?;
+            }
+            next_scope_id = scope_ids.next();
+        }
+        // Skip the candidate itself
+        if end.is_some() && next_scope_id == end {
+            next_scope_id = scope_ids.next();
+        }
+        Ok(())
+    };
+    
+    match searcher {
+        // Without a content pattern, list the files themselves
+        None => {
+            while let Some(path) = next_path() {
+                if !limit.take() {
+                    break;
+                }
+                let display_path = format_path(&path, args.path_format, cwd);
+                print_path(stdout, &display_path, "", use_color)?;
+            }
+        }
+        // Files are searched in parallel and printed in candidate order
+        Some(ref searcher) => searcher.search_parallel(std::iter::from_fn(&mut next_path), threads, |file| {
+            let fileid = searched_ids.borrow_mut().pop_front();
+            // Format path according to user preference
+            let display_path = format_path(&file.path, args.path_format, cwd);
+            if let Some(ref e) = file.error
+                && args.verbose {
+                eprintln!("Warning: failed to search {}: {}", display_path, e);
+            }
+            let matched = file.lines.iter().any(|m| !m.context);
+            
+            if args.files_with_matches || args.count {
+                if matched && limit.take() {
+                    let count = file.lines.iter().filter(|m| !m.context).count();
+                    let suffix = if args.count { format!(":{}", count) } else { String::new() };
+                    print_path(stdout, &display_path, &suffix, use_color)?;
+                }
+                return Ok(!limit.reached());
+            }
+            if args.files_without_match {
+                print_non_candidates(stdout, limit, fileid)?;
+                if !matched && file.error.is_none() && limit.take() {
+                    print_path(stdout, &display_path, "", use_color)?;
+                }
+                return Ok(!limit.reached());
+            }
+            
+            let shown = &file.lines[..limit.take_lines(&file.lines, after_context)];
+            if args.json {
+                let json_path = json_path(&file.path, &display_path);
+                let mut stats = JsonStats {
+                    elapsed: file.elapsed,
+                    searches: 1,
+                    bytes_searched: file.bytes_read,
+                    ..Default::default()
+                };
+                for (i, m) in shown.iter().enumerate() {
+                    if i == 0 {
+                        stats.bytes_printed += write_json(stdout, "begin", json!({ "path": json_path }))?;
+                    }
+                    if m.context {
+                        stats.bytes_printed += write_json(stdout, "context", json_match(&json_path, m))?;
+                        continue;
+                    }
+                    stats.matched_lines += 1;
+                    stats.matches += m.submatches.len() as u64;
+                    stats.bytes_printed += write_json(stdout, "match", json_match(&json_path, m))?;
+                }
+                if stats.matched_lines > 0 {
+                    stats.searches_with_match = 1;
+                    let end = json!({ "path": json_path, "binary_offset": null, "stats": stats.to_json() });
+                    stats.bytes_printed += write_json(stdout, "end", end)?;
+                }
+                json_stats.add(&stats);
+                return Ok(!limit.reached());
+            }
+            
+            for m in shown {
+                // Groups of lines that are not adjacent are separated by --
+                if show_context {
+                    if last_line.as_ref().is_some_and(|(path, line)| *path != file.path || *line != m.line_number - 1) {
+                        print_separator(stdout, use_color)?;
+                    }
+                    *last_line = Some((file.path.clone(), m.last_line_number));
+                }
+                // A match spanning lines prints each of them
+                for line in m.lines() {
+                    let line_num_opt = if args.line_number { Some(line.line_number) } else { None };
+                    let printed = if args.only_matching {
+                        print_only_matching(stdout, &display_path, line_num_opt, &line, use_color)
+                    } else {
+                        print_highlighted_line(stdout, &display_path, line_num_opt, &line, use_color)
+                    };
+                    if let Err(e) = printed
+                        && args.verbose {
+                        eprintln!("Warning: failed to write output: {}", e);
+                    }
+                }
+            }
+            Ok(!limit.reached())
+        })?,
+    }
+    
+    // The files after the last candidate
+    if args.files_without_match && !limit.reached() {
+        print_non_candidates(stdout, limit, None)?;
+    }
+    
+    if args.verbose {
+        eprintln!("post query identified {} possible files", num_candidates);
     }
     
     Ok(())
 }
 
+/// Open the delta index at `path` if there is one. It is published
+/// atomically, so one that exists but cannot be opened is reported and
+/// left out.
+fn open_delta(path: &str) -> Option<Index> {
+    match Index::open(path) {
+        Ok(delta) => Some(delta),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            eprintln!("csearch: failed to open index {}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Add the roots of `index`, read from `index_path`, to `covered`.
+fn push_roots(covered: &mut Vec<String>, index_path: &str, index: &Index) {
+    let index_dir = Path::new(index_path).parent().unwrap_or(Path::new("."));
+    let mut roots = index.roots();
+    while let Some(root) = roots.next() {
+        covered.push(resolve_path(index_dir, &root).to_string_lossy().to_string());
+    }
+}
+
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    
+    if let Some(Command::Serve(serve_args)) = args.command.take() {
+        return serve(serve_args);
+    }
     
     if args.list_file_types {
         let mut builder = TypesBuilder::new();
@@ -191,16 +817,13 @@ fn main() -> Result<()> {
         }
         return Ok(());
     }
-
-    let pattern_str = match args.pattern {
-        Some(p) => p,
-        None => {
-            use clap::CommandFactory;
-            let mut cmd = Args::command();
-            cmd.print_help()?;
-            return Ok(());
-        }
-    };
+    
+    if args.pattern.is_none() && args.files.is_none() {
+        use clap::CommandFactory;
+        let mut cmd = Args::command();
+        cmd.print_help()?;
+        return Ok(());
+    }
     
     let types_matcher = if let Some(ref ftype) = args.file_type {
         let mut builder = TypesBuilder::new();
@@ -211,54 +834,27 @@ fn main() -> Result<()> {
         None
     };
     
-    // Open index
-    let index_path = if let Some(p) = args.index {
-        p
-    } else {
-        find_index_file(false)?
+    // The directory the search is scoped to, if any
+    let scope_dir = match args.dir {
+        // A directory that no longer exists may still be in the index
+        Some(ref dir) => Some(Path::new(dir).canonicalize().or_else(|_| std::path::absolute(dir))
+             .context(format!("failed to resolve directory {}", dir))?),
+        None if args.pwd => Some(std::env::current_dir().context("failed to get current directory")?
+             .canonicalize().context("failed to canonicalize current directory")?),
+        None => None,
     };
     
-    let index = Index::open(&index_path).context(format!("failed to open index {}", index_path))?;
-    let index_dir = Path::new(&index_path).parent().unwrap_or(Path::new("."));
-    
-    let pattern = if args.ignore_case {
-        // Check if pattern already has (?i) to avoid double prefix if user provided it?
-        // But prepending is safe usually.
-        format!("(?i){}", pattern_str)
+    let index_paths = if args.stack {
+        find_stacked_index_files()?
+    } else if args.index.is_empty() {
+        find_index_files()?
     } else {
-        pattern_str.clone()
+        args.index.clone()
     };
-    
-    if args.verbose {
-        eprintln!("pattern: {}", pattern);
-        eprintln!("Index info: num_name={}, num_post={}, name_data={}, name_index={}, post_data={}, post_index={}", 
-                  index.num_name, index.num_post, index.name_data, index.name_index, index.post_data, index.post_index);
-    }
-
-    let q = regexp::analyze_regexp(&pattern).context("failed to analyze regexp")?;
-    
-    if args.verbose {
-        eprintln!("query: {:?}", q);
-    }
-    
-    let post = index.posting_query(&q);
-    
-    if args.verbose {
-        eprintln!("post query identified {} possible files", post.len());
-        // Debug: check for duplicates
-        let unique: std::collections::HashSet<_> = post.iter().collect();
-        if unique.len() != post.len() {
-            eprintln!("WARNING: posting_query returned {} duplicates!", post.len() - unique.len());
-        }
+    if args.verbose && index_paths.len() > 1 {
+        eprintln!("indexes: {}", index_paths.join(", "));
     }
     
-    let cwd_canonical = if args.pwd {
-        Some(std::env::current_dir().context("failed to get current directory")?
-             .canonicalize().context("failed to canonicalize current directory")?)
-    } else {
-        None
-    };
-    
     // Get cwd for path formatting
     let cwd = std::env::current_dir().unwrap_or_else(|_| std::path::PathBuf::from("."));
     let cwd_for_format = cwd.canonicalize().unwrap_or(cwd);
@@ -269,97 +865,71 @@ fn main() -> Result<()> {
         ColorMode::Always => ColorChoice::Always,
         ColorMode::Never => ColorChoice::Never,
     };
-    let mut stdout = StandardStream::stdout(color_choice);
     let use_color = match args.color {
         ColorMode::Always => true,
         ColorMode::Never => false,
         ColorMode::Auto => atty::is(atty::Stream::Stdout),
+    } && !args.json;
+    
+    let search_started = Instant::now();
+    let mut out = Output {
+        stdout: StandardStream::stdout(color_choice),
+        use_color,
+        cwd: cwd_for_format,
+        limit: ResultLimit { max: args.max_results, count: 0 },
+        last_line: None,
+        json_stats: JsonStats::default(),
+        seen: (index_paths.len() > 1).then(HashSet::new),
+        covered: Vec::new(),
     };
     
-    let re = RegexBuilder::new(&pattern)
-        .case_insensitive(args.ignore_case)
-        .build()
-        .context("failed to compile regex")?;
-        
-    for fileid in post {
-        let name = index.name(fileid as usize);
-        if name.is_empty() {
-            if args.verbose {
-                eprintln!("Warning: empty filename for fileid {}", fileid);
-            }
-            continue;
-        }
-        
-        let raw_path = Path::new(&name);
-        let resolved_path = if raw_path.is_relative() {
-            let p = index_dir.join(raw_path);
-            p.components()
-                .filter(|c| !matches!(c, std::path::Component::CurDir))
-                .collect::<std::path::PathBuf>()
-        } else {
-            raw_path.to_path_buf()
-        };
-        
-        if let Some(ref cwd) = cwd_canonical {
-             let abs_path = match resolved_path.canonicalize() {
-                 Ok(p) => p,
-                 Err(_) => continue, // Skip if file doesn't exist or can't be canonicalized
-             };
-             if !abs_path.starts_with(cwd) {
-                 continue;
-             }
-        }
-        
-        let path = resolved_path.as_path();
-        
-        if let Some(ref matcher) = types_matcher {
-             if !matches!(matcher.matched(path, false), Match::Whitelist(_)) {
-                 continue;
-             }
-        }
-        
-        let file = match File::open(path) {
-            Ok(f) => f,
+    // An index that cannot be opened is reported and the others are
+    // still searched
+    let mut failed = 0;
+    for (i, index_path) in index_paths.iter().enumerate() {
+        let index = match Index::open(index_path) {
+            Ok(index) => index,
+            Err(e) if index_paths.len() == 1 => return Err(e).context(format!("failed to open index {}", index_path)),
             Err(e) => {
-                if args.verbose {
-                    eprintln!("Warning: failed to open {}: {}", path.display(), e);
-                }
+                eprintln!("csearch: failed to open index {}: {}", index_path, e);
+                failed += 1;
                 continue;
             }
         };
-        
-        let reader = BufReader::new(file);
-        
-        // Format path according to user preference
-        let display_path = format_path(path, args.path_format, &cwd_for_format);
-        
-        let mut line_num = 0;
-        for line_res in reader.split(b'\n') {
-            line_num += 1;
-            match line_res {
-                Ok(line_bytes) => {
-                    if re.is_match(&line_bytes) {
-                        // Convert to string (lossy)
-                        let line = String::from_utf8_lossy(&line_bytes);
-                        // Remove trailing \r if present
-                        let line = line.trim_end_matches('\r');
-                        let line_num_opt = if args.line_number { Some(line_num) } else { None };
-                        if let Err(e) = print_highlighted_line(&mut stdout, &display_path, line_num_opt, line, &re, use_color) {
-                            if args.verbose {
-                                eprintln!("Warning: failed to write output: {}", e);
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    if args.verbose {
-                        eprintln!("Warning: error reading line {} from {}: {}", line_num, display_path, e);
-                    }
-                    break;
-                }
+        if args.explain && index_paths.len() > 1 {
+            let sep = if i > 0 { "\n" } else { "" };
+            writeln!(out.stdout, "{}index: {}", sep, index_path)?;
+        }
+        // Changes that `cindex --watch` has not folded into the index yet
+        // are searched first, and replace the files of the index
+        let covered = out.covered.len();
+        let delta_path = delta_index_file(index_path);
+        if !args.explain
+            && let Some(delta) = open_delta(&delta_path) {
+            search_index(&args, &delta_path, &delta, types_matcher.as_ref(), scope_dir.as_deref(), &mut out)?;
+            if out.limit.reached() {
+                break;
             }
+            push_roots(&mut out.covered, &delta_path, &delta);
+        }
+        search_index(&args, index_path, &index, types_matcher.as_ref(), scope_dir.as_deref(), &mut out)?;
+        out.covered.truncate(covered);
+        if args.stack {
+            push_roots(&mut out.covered, index_path, &index);
         }
+        if out.limit.reached() {
+            break;
+        }
+    }
+    
+    if args.json {
+        let elapsed = search_started.elapsed();
+        let summary = json!({ "elapsed_total": json_duration(elapsed), "stats": out.json_stats.to_json() });
+        write_json(&mut out.stdout, "summary", summary)?;
     }
     
+    if failed > 0 {
+        anyhow::bail!("{} of {} indexes could not be searched", failed, index_paths.len());
+    }
     Ok(())
 }
@@ -0,0 +1,150 @@
+use std::fmt;
+use std::io;
+use crate::index::postings::{AllIter, AndIter, EmptyIter, ListIter, OrIter, PostingIterator};
+use crate::index::read::{trigram_u32, Index, PostReader, PostingTable};
+use crate::index::regexp::{explain_regexp, Query, QueryOp};
+
+/// What a node of an explained query is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExplainKind {
+    /// Every file.
+    All,
+    /// No file.
+    None,
+    And,
+    Or,
+    /// The files holding one trigram.
+    Trigram(String),
+}
+
+/// A node of the trigram query of a pattern, with the number of files it
+/// selects out of the whole index.
+#[derive(Debug, Clone)]
+pub struct ExplainNode {
+    pub kind: ExplainKind,
+    pub count: usize,
+    pub children: Vec<ExplainNode>,
+}
+
+/// How the candidates of a pattern are found: the trigram query, how many
+/// files each part of it selects, and why it selects every file when it
+/// does.
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    pub pattern: String,
+    pub query: Query,
+    pub tree: ExplainNode,
+    /// The parts of the pattern that give no trigrams.
+    pub notes: Vec<String>,
+    /// Number of files in the index.
+    pub total: usize,
+    /// Number of files the search is restricted to, if it is.
+    pub scope: Option<usize>,
+    /// Number of files the planned query leaves to check.
+    pub candidates: usize,
+    /// Number of those candidates that the whole query would rule out:
+    /// the planner skips posting lists that cost more to read than
+    /// checking the files they could rule out.
+    pub skipped: usize,
+}
+
+impl Index {
+    /// Explains how the candidates of `pattern` are selected, only among
+    /// `restrict` when given, which must be sorted.
+    pub fn explain(&self, pattern: &str, restrict: Option<Vec<u32>>) -> io::Result<Explanation> {
+        let (query, notes) = explain_regexp(pattern)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let table = self.content_postings();
+        let tree = self.explain_node(&table, &query);
+        let scope = restrict.as_ref().map(|r| r.len());
+        let exact = match restrict.clone() {
+            Some(r) => AndIter::new(vec![Box::new(ListIter::new(r)), self.exact_postings(&table, &query)]).collect_ids().len(),
+            None => tree.count,
+        };
+        let candidates = self.posting_iter(&query, restrict).collect_ids().len();
+        Ok(Explanation {
+            pattern: pattern.to_string(),
+            query,
+            tree,
+            notes,
+            total: self.num_name,
+            scope,
+            candidates,
+            skipped: candidates.saturating_sub(exact),
+        })
+    }
+    
+    fn explain_node(&self, table: &PostingTable, q: &Query) -> ExplainNode {
+        let kind = match q.op {
+            QueryOp::All => ExplainKind::All,
+            QueryOp::None => ExplainKind::None,
+            QueryOp::And => ExplainKind::And,
+            QueryOp::Or => ExplainKind::Or,
+        };
+        let children = q.trigram.iter()
+            .map(|t| ExplainNode {
+                kind: ExplainKind::Trigram(t.clone()),
+                count: self.list_count(table, trigram_u32(t)),
+                children: Vec::new(),
+            })
+            .chain(q.sub.iter().map(|sub| self.explain_node(table, sub)))
+            .collect();
+        let count = self.exact_postings(table, q).collect_ids().len();
+        ExplainNode { kind, count, children }
+    }
+    
+    /// The files that hold the trigrams of `q`, reading every posting list
+    /// unlike the planner.
+    fn exact_postings(&self, table: &PostingTable, q: &Query) -> Box<dyn PostingIterator + '_> {
+        let its: Vec<Box<dyn PostingIterator>> = q.trigram.iter()
+            .map(|t| Box::new(PostReader::with_table(self, table, trigram_u32(t), None)) as Box<dyn PostingIterator>)
+            .chain(q.sub.iter().map(|sub| self.exact_postings(table, sub)))
+            .collect();
+        match q.op {
+            QueryOp::All => Box::new(AllIter::new(self.num_name as u32)),
+            QueryOp::None => Box::new(EmptyIter),
+            _ if its.is_empty() => Box::new(EmptyIter),
+            QueryOp::And => Box::new(AndIter::new(its)),
+            QueryOp::Or => Box::new(OrIter::new(its)),
+        }
+    }
+}
+
+fn write_node(f: &mut fmt::Formatter<'_>, node: &ExplainNode, depth: usize) -> fmt::Result {
+    let label = match &node.kind {
+        ExplainKind::All => "ALL".to_string(),
+        ExplainKind::None => "NONE".to_string(),
+        ExplainKind::And => "AND".to_string(),
+        ExplainKind::Or => "OR".to_string(),
+        ExplainKind::Trigram(t) => format!("{:?}", t),
+    };
+    writeln!(f, "{:indent$}{}: {} files", "", label, node.count, indent = depth * 2)?;
+    for child in &node.children {
+        write_node(f, child, depth + 1)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "pattern: {}", self.pattern)?;
+        writeln!(f, "query:")?;
+        write_node(f, &self.tree, 1)?;
+        match self.scope {
+            Some(scope) => writeln!(f, "candidates: {} of {} files in scope, {} in the index", self.candidates, scope, self.total)?,
+            None => writeln!(f, "candidates: {} of {} files", self.candidates, self.total)?,
+        }
+        if self.tree.kind == ExplainKind::All {
+            writeln!(f, "every file is a candidate because:")?;
+            if self.notes.is_empty() {
+                writeln!(f, "  no part of the pattern has 3 known characters in a row")?;
+            }
+            for note in &self.notes {
+                writeln!(f, "  {}", note)?;
+            }
+        } else if self.skipped > 0 {
+            writeln!(f, "{} more files are checked because the planner skipped posting lists that cost more to read than the files", self.skipped)?;
+        }
+        Ok(())
+    }
+}
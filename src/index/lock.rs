@@ -0,0 +1,73 @@
+use std::fs::{self, File, TryLockError};
+use std::io;
+use std::path::Path;
+
+/// An advisory lock held by a process that writes an index, so that two
+/// indexers never update the same index at once. It is an exclusive lock on
+/// `<index>.lock`, released when the value is dropped or the process exits.
+/// Readers don't take it: they keep using the index they opened until a
+/// writer atomically replaces it.
+pub struct IndexLock {
+    _file: File,
+}
+
+impl IndexLock {
+    pub fn path_for(index_file: &str) -> String {
+        format!("{}.lock", index_file)
+    }
+
+    /// Takes the lock for `index_file`. If another process holds it, this
+    /// blocks until it is released when `wait` is set, and otherwise fails
+    /// with `io::ErrorKind::WouldBlock`. Once the lock is taken, temporary
+    /// files left by a writer of the index that was killed are removed.
+    pub fn acquire(index_file: &str, wait: bool) -> io::Result<Self> {
+        let path = Self::path_for(index_file);
+        // The lock file itself is never removed: a process could still be
+        // waiting on the old inode while another locks a new one.
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) if wait => file.lock()?,
+            Err(TryLockError::WouldBlock) => {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, format!("{} is held by another indexer", path)));
+            }
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+        remove_stale_temps(index_file);
+        Ok(IndexLock { _file: file })
+    }
+}
+
+/// Removes the `.<index>.XXXXXX.tmp` files that `IndexBuffer::for_publish`
+/// creates next to `index_file`. Only the lock holder writes them, so any
+/// found are left by a process that died before publishing or dropping them.
+fn remove_stale_temps(index_file: &str) {
+    let path = Path::new(index_file);
+    let Some(name) = path.file_name() else {
+        return;
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!(".{}.", name.to_string_lossy());
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let file_name = entry.file_name();
+        let Some(random) = file_name.to_str()
+            .and_then(|n| n.strip_prefix(&prefix))
+            .and_then(|n| n.strip_suffix(".tmp")) else {
+            continue;
+        };
+        if !random.is_empty() && random.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
@@ -1,250 +1,472 @@
 use std::io;
-use std::cmp::Ordering;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
 use crate::index::read::{Index, DeltaReader};
-use crate::index::write::{IndexBuffer, PathWriter, PathWriterState, PostDataWriter, IndexPath};
+use crate::index::write::{IndexBuffer, PathWriter, PathWriterState, PostDataWriter, IndexPath, FileMeta, cmp_paths, path_within, write_name_postings};
+use memmap2::Mmap;
 
 // Helper to check if name is covered by any root
-fn is_shadowed(name: &str, roots: &[String]) -> bool {
+pub fn is_shadowed(name: &str, roots: &[String]) -> bool {
     for root in roots {
-        if name.starts_with(root) {
+        if path_within(name, root) {
             return true;
         }
     }
     false
 }
 
-pub fn merge(dst_path: &str, src1_path: &str, src2_path: &str) -> io::Result<()> {
-    let ix1 = Index::open(src1_path)?;
-    let ix2 = Index::open(src2_path)?;
+/// What a merge did besides combining the files.
+#[derive(Debug, Default)]
+pub struct MergeReport {
+    /// Roots of the old index that are, or lie within, a root of the new
+    /// one. All their files were replaced, so only the new root is kept in
+    /// the merged roots.
+    pub superseded_roots: Vec<String>,
+}
 
-    // 1. Load roots from ix2 to determine shadowing
-    let mut ix2_roots = Vec::new();
-    let mut r = ix2.roots();
+fn read_roots(ix: &Index) -> Vec<String> {
+    let mut roots = Vec::new();
+    let mut r = ix.roots();
     while let Some(root) = r.next() {
-        ix2_roots.push(root);
+        roots.push(root);
     }
+    roots
+}
 
-    // 2. Build ID Map for ix1 (Old -> New)
+pub fn merge(dst_path: &str, src1_path: &str, src2_path: &str) -> io::Result<MergeReport> {
+    merge_retaining(dst_path, src1_path, src2_path, &HashMap::new())
+}
+
+/// Like `merge`, but files of src1 listed in `retain` survive even when a
+/// root of src2 covers them, with their metadata replaced by the given value.
+/// This is how an incremental `cindex` run keeps the postings of unchanged
+/// files without re-reading them.
+pub fn merge_retaining(dst_path: &str, src1_path: &str, src2_path: &str, retain: &HashMap<usize, FileMeta>) -> io::Result<MergeReport> {
+    let ix1 = Index::open(src1_path)?;
+    let ix2 = Index::open(src2_path)?;
+    merge_indexes(dst_path, &[ix1, ix2], retain, None)
+}
+
+/// Folds the delta index `delta_path` into `main_path`. The roots of the
+/// delta name the files and directories that changed: the files of main
+/// under them are replaced by those of the delta, or dropped when the delta
+/// doesn't have them. Unlike with `merge`, the roots of main are kept as
+/// they are and the delta's are not added to them.
+pub fn fold_delta(dst_path: &str, main_path: &str, delta_path: &str) -> io::Result<MergeReport> {
+    let main = Index::open(main_path)?;
+    let delta = Index::open(delta_path)?;
+    let roots = read_roots(&main);
+    merge_indexes(dst_path, &[main, delta], &HashMap::new(), Some(roots))
+}
+
+/// Merges any number of indexes into `dst_path` in a single pass. Later
+/// sources take precedence: a file is dropped when a later source has the
+/// same name or one of its roots covers the file.
+pub fn merge_many(dst_path: &str, src_paths: &[&str]) -> io::Result<MergeReport> {
+    let srcs = src_paths.iter().map(Index::open).collect::<io::Result<Vec<_>>>()?;
+    merge_indexes(dst_path, &srcs, &HashMap::new(), None)
+}
+
+/// The k-way merge behind `merge`, `merge_retaining`, `merge_many` and
+/// `fold_delta`. `retain` applies to the file ids of the first source.
+/// The merged index gets `roots` when given, and otherwise the union of
+/// the roots of the sources.
+fn merge_indexes(dst_path: &str, srcs: &[Index], retain: &HashMap<usize, FileMeta>, roots: Option<Vec<String>>) -> io::Result<MergeReport> {
+    // Names are merged in index path order, which older indexes don't use
+    if srcs.iter().any(|ix| ix.version < 3) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "cannot merge an index in an old format, rebuild it first"));
+    }
+    
+    // 1. Load roots. A source is shadowed by the roots of all later sources.
+    let src_roots: Vec<Vec<String>> = srcs.iter().map(read_roots).collect();
+    let mut later_roots = vec![Vec::new(); srcs.len()];
+    for i in (0..srcs.len().saturating_sub(1)).rev() {
+        let mut roots = later_roots[i + 1].clone();
+        roots.extend(src_roots[i + 1].iter().cloned());
+        later_roots[i] = roots;
+    }
+    
+    // 2. ID maps for each source (Old -> New)
     // -1 indicates shadowed/deleted.
-    let mut id_map = vec![-1; ix1.num_name];
-    let mut ix2_map = Vec::with_capacity(ix2.num_name);
+    let mut id_maps: Vec<Vec<i32>> = srcs.iter().map(|ix| vec![-1; ix.num_name]).collect();
     
     // Prepare Output Buffers
-    let mut main_buf = IndexBuffer::new(dst_path)?;
-    let mut name_buf = IndexBuffer::new("")?;
-    let mut name_index_buf = IndexBuffer::new("")?;
-    let mut post_buf = IndexBuffer::new("")?;
-    let mut post_index_buf = IndexBuffer::new("")?;
-    
-    main_buf.write_string("csearch index 2\n")?;
+    let mut main_buf = IndexBuffer::for_publish(dst_path)?;
+    let mut out = Sections::new()?;
     
     // 3. Write Merged Roots
-    // Merge ix1.roots and ix2.roots
-    let roots_off = main_buf.offset();
-    let mut roots: Vec<String> = Vec::new();
-    {
-        let mut r1 = ix1.roots();
-        while let Some(p) = r1.next() { roots.push(p); }
-        let mut r2 = ix2.roots();
-        while let Some(p) = r2.next() { roots.push(p); }
-    }
-    roots.sort(); 
-    roots.dedup(); // Remove duplicates
-    
-    {
-        let mut root_state = PathWriterState::new(16);
-        let mut pw = PathWriter::new(&mut main_buf, None, &mut root_state);
-        for r in &roots {
-            pw.write(&IndexPath::new(r.clone()))?;
+    // Take the union of all roots, dropping the ones a later source supersedes
+    let mut report = MergeReport::default();
+    let mut roots = match roots {
+        Some(roots) => roots,
+        None => {
+            let mut roots = Vec::new();
+            for (i, src) in src_roots.iter().enumerate() {
+                for root in src {
+                    if !is_shadowed(root, &later_roots[i]) {
+                        roots.push(root.clone());
+                    } else {
+                        report.superseded_roots.push(root.clone());
+                    }
+                }
+            }
+            roots
         }
-    }
-    let roots_count = roots.len(); // Go implementation counts paths
-    main_buf.align(16)?;
+    };
+    roots.sort_by(|a, b| cmp_paths(a, b));
+    roots.dedup(); // Remove duplicates
+    report.superseded_roots.sort_by(|a, b| cmp_paths(a, b));
+    report.superseded_roots.dedup();
+    let (roots_off, roots_count) = write_header_and_roots(&mut main_buf, &roots)?;
     
     // 4. Merge Names
-    let name_off = main_buf.offset();
-    let mut name_count = 0;
-    
     {
         let mut name_state = PathWriterState::new(16);
-        let mut pw = PathWriter::new(&mut name_buf, Some(&mut name_index_buf), &mut name_state);
-        
-        let mut r1 = ix1.names_at(0, ix1.num_name);
-        let mut r2 = ix2.names_at(0, ix2.num_name);
+        let mut pw = PathWriter::new(&mut out.name, Some(&mut out.name_index), &mut name_state);
         
-        let mut n1 = r1.next();
-        let mut n2 = r2.next();
-        
-        let mut i1 = 0;
-        let mut _i2 = 0; // tracking for debugging if needed
+        let mut readers: Vec<_> = srcs.iter().map(|ix| ix.names_at(0, ix.num_name)).collect();
+        let mut next_ids = vec![0; srcs.len()];
+        let mut heap = BinaryHeap::new();
+        for (i, r) in readers.iter_mut().enumerate() {
+            if let Some(s) = r.next() {
+                heap.push(Reverse((IndexPath::new(s), i)));
+            }
+        }
         
-        while n1.is_some() || n2.is_some() {
-            let mut take_1 = false;
-            
-            if n1.is_none() {
-                // take_2
-            } else if n2.is_none() {
-                take_1 = true;
-            } else {
-                let s1 = n1.as_ref().unwrap();
-                let s2 = n2.as_ref().unwrap();
-                match s1.cmp(s2) {
-                    Ordering::Less => take_1 = true,
-                    Ordering::Greater => {}, // take_2
-                    Ordering::Equal => {
-                        // take_2 (shadows s1)
-                    }
+        // Entries with equal names pop in source order, so the last one wins
+        while let Some(Reverse((name, first))) = heap.pop() {
+            let mut winner = first;
+            let mut same = vec![first];
+            while let Some(Reverse((next, i))) = heap.peek() {
+                if next.s != name.s {
+                    break;
                 }
+                winner = *i;
+                same.push(*i);
+                heap.pop();
             }
             
-            if take_1 {
-                let s = n1.unwrap();
-                // Check shadowing
-                if !is_shadowed(&s, &ix2_roots) {
-                    pw.write(&IndexPath::new(s.clone()))?;
-                    id_map[i1] = name_count as i32;
-                    name_count += 1;
-                }
-                // else id_map[i1] = -1
-                
-                i1 += 1;
-                n1 = r1.next();
-            } else {
-                // take_2
-                let s = n2.unwrap();
-                pw.write(&IndexPath::new(s.clone()))?;
-                ix2_map.push(name_count as i32);
-                
-                // If s1 was equal, we need to skip it
-                if n1.is_some() && n1.as_ref().unwrap() == &s {
-                     // s1 is shadowed
-                     i1 += 1;
-                     n1 = r1.next();
+            let id = next_ids[winner];
+            let kept = if winner == 0 { retain.get(&id) } else { None };
+            if kept.is_some() || !is_shadowed(&name.s, &later_roots[winner]) {
+                let meta = kept.copied().or_else(|| srcs[winner].file_meta(id)).unwrap_or_default();
+                meta.write(&mut out.meta)?;
+                id_maps[winner][id] = out.name_count;
+                out.name_count += 1;
+                pw.write(&name)?;
+            }
+            
+            for i in same {
+                next_ids[i] += 1;
+                if let Some(s) = readers[i].next() {
+                    heap.push(Reverse((IndexPath::new(s), i)));
                 }
-                
-                _i2 += 1;
-                n2 = r2.next();
-                name_count += 1;
             }
         }
     }
     
     // 5. Merge Postings
+    let sources: Vec<_> = srcs.iter().zip(&id_maps).map(|(ix, m)| (ix, m.as_slice())).collect();
+    out.write_postings(&sources)?;
     
-    // 5. Merge Postings
-    let mut trigram_count = 0;
+    // 6. Write Trailer
+    out.finish(&mut main_buf, roots_off, roots_count)?;
+    Ok(report)
+}
+
+/// Rewrites the index at `src_path` into `dst_path` without the files for
+/// which `remove` returns true, renumbering the remaining file ids the same
+/// way `merge` does. The root list is replaced by `roots`. Returns the
+/// number of files removed. Rewriting an index in place when that removes
+/// nothing leaves the file untouched.
+pub fn remove_files<F>(dst_path: &str, src_path: &str, roots: &[String], mut remove: F) -> io::Result<usize>
+where F: FnMut(&str) -> bool {
+    let ix = Index::open(src_path)?;
+    let mut id_map = vec![-1; ix.num_name];
+    
+    let mut main_buf = IndexBuffer::for_publish(dst_path)?;
+    let mut out = Sections::new()?;
+    
+    let mut roots = roots.to_vec();
+    roots.sort_by(|a, b| cmp_paths(a, b));
+    roots.dedup();
+    let (roots_off, roots_count) = write_header_and_roots(&mut main_buf, &roots)?;
+    
+    {
+        let mut name_state = PathWriterState::new(16);
+        let mut pw = PathWriter::new(&mut out.name, Some(&mut out.name_index), &mut name_state);
+        let mut r = ix.names_at(0, ix.num_name);
+        let mut i = 0;
+        while let Some(s) = r.next() {
+            if !remove(&s) {
+                pw.write(&IndexPath::new(s))?;
+                ix.file_meta(i).unwrap_or_default().write(&mut out.meta)?;
+                id_map[i] = out.name_count;
+                out.name_count += 1;
+            }
+            i += 1;
+        }
+    }
+    let removed = ix.num_name - out.name_count as usize;
+    if removed == 0 && dst_path == src_path && roots == read_roots(&ix) {
+        return Ok(0);
+    }
+    
+    out.write_postings(&[(&ix, &id_map)])?;
+    out.finish(&mut main_buf, roots_off, roots_count)?;
+    Ok(removed)
+}
+
+fn write_header_and_roots(main_buf: &mut IndexBuffer, roots: &[String]) -> io::Result<(u64, usize)> {
+    main_buf.write_header()?;
     
+    let roots_off = main_buf.start_section();
     {
-        let mut p1 = ix1.post_map_iter();
-        let mut p2 = ix2.post_map_iter();
+        let mut root_state = PathWriterState::new(16);
+        let mut pw = PathWriter::new(main_buf, None, &mut root_state);
+        for r in roots {
+            pw.write(&IndexPath::new(r.clone()))?;
+        }
+    }
+    let roots_count = roots.len(); // Go implementation counts paths
+    main_buf.align(16)?;
+    Ok((roots_off, roots_count))
+}
+
+/// Temporary buffers for the sections of a rewritten index, copied into
+/// the output file behind the roots by `finish`.
+struct Sections {
+    name: IndexBuffer,
+    name_index: IndexBuffer,
+    post: IndexBuffer,
+    post_index: IndexBuffer,
+    meta: IndexBuffer,
+    name_count: i32,
+    trigram_count: usize,
+}
+
+impl Sections {
+    fn new() -> io::Result<Self> {
+        Ok(Sections {
+            name: IndexBuffer::new("")?,
+            name_index: IndexBuffer::new("")?,
+            post: IndexBuffer::new("")?,
+            post_index: IndexBuffer::new("")?,
+            meta: IndexBuffer::new("")?,
+            name_count: 0,
+            trigram_count: 0,
+        })
+    }
+    
+    /// Writes the union of the posting lists of `sources`, mapping each
+    /// source's file ids through its id map. Ids mapped to -1 are dropped.
+    fn write_postings(&mut self, sources: &[(&Index, &[i32])]) -> io::Result<()> {
+        let mut iters: Vec<_> = sources.iter().map(|(ix, _)| ix.post_map_iter()).collect();
+        let mut nexts: Vec<_> = iters.iter_mut().map(|p| p.next()).collect();
         
-        let mut next1 = p1.next();
-        let mut next2 = p2.next();
+        // Sources ordered by their current trigram
+        let mut heap: BinaryHeap<_> = nexts.iter().enumerate()
+            .filter_map(|(i, n)| n.map(|(t, _, _)| Reverse((t, i))))
+            .collect();
         
-        let mut w = PostDataWriter::new(&mut post_buf, Some(&mut post_index_buf));
+        let mut w = PostDataWriter::new(&mut self.post, Some(&mut self.post_index));
+        let mut ids = Vec::new();
         
-        while next1.is_some() || next2.is_some() {
-            let mut t = u32::MAX;
-            if let Some((t1, _, _)) = next1 { t = std::cmp::min(t, t1); }
-            if let Some((t2, _, _)) = next2 { t = std::cmp::min(t, t2); }
-            
-            w.trigram(t)?;
-            trigram_count += 1;
-            
-            let mut ids = Vec::new();
-            
-            if let Some((t1, count, offset)) = next1 {
-                if t1 == t {
-                    if ix1.post_data + offset + 3 <= ix1.mmap.len() {
-                        let data = &ix1.mmap[ix1.post_data + offset + 3 ..];
-                        let mut delta = DeltaReader::new(data);
-                        let mut fileid = -1;
-                        for _ in 0..count {
-                            if let Some(d) = delta.next() {
-                                fileid += d as i32;
-                                if fileid >= 0 && (fileid as usize) < id_map.len() {
-                                    let new_id = id_map[fileid as usize];
-                                    if new_id != -1 {
-                                        ids.push(new_id);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    next1 = p1.next();
+        while let Some(&Reverse((t, _))) = heap.peek() {
+            ids.clear();
+            while let Some(&Reverse((ti, i))) = heap.peek() {
+                if ti != t {
+                    break;
                 }
-            }
-            
-            if let Some((t2, count, offset)) = next2 {
-                if t2 == t {
-                    if ix2.post_data + offset + 3 <= ix2.mmap.len() {
-                        let data = &ix2.mmap[ix2.post_data + offset + 3 ..];
-                        let mut delta = DeltaReader::new(data);
-                        let mut fileid = -1;
-                        for _ in 0..count {
-                            if let Some(d) = delta.next() {
-                                fileid += d as i32;
-                                if fileid >= 0 && (fileid as usize) < ix2_map.len() {
-                                    let new_id = ix2_map[fileid as usize];
+                heap.pop();
+                let (ix, id_map) = sources[i];
+                let Some((_, count, offset)) = nexts[i] else { continue };
+                if ix.post_data + offset + 3 <= ix.mmap.len() {
+                    let data = &ix.mmap[ix.post_data + offset + 3 ..];
+                    let mut delta = DeltaReader::new(data);
+                    let mut fileid = -1;
+                    for _ in 0..count {
+                        if let Some(d) = delta.next() {
+                            fileid += d as i32;
+                            if fileid >= 0 && (fileid as usize) < id_map.len() {
+                                let new_id = id_map[fileid as usize];
+                                if new_id != -1 {
                                     ids.push(new_id);
                                 }
                             }
                         }
                     }
-                    next2 = p2.next();
+                }
+                nexts[i] = iters[i].next();
+                if let Some((tn, _, _)) = nexts[i] {
+                    heap.push(Reverse((tn, i)));
                 }
             }
             
             ids.sort();
             ids.dedup();
             
-            for id in ids {
+            // Lists left empty by removed files are dropped entirely
+            if ids.is_empty() {
+                continue;
+            }
+            w.trigram(t)?;
+            self.trigram_count += 1;
+            for &id in &ids {
                 w.fileid(id)?;
             }
             w.end_trigram()?;
         }
-        w.flush()?;
-        }
-        
-        // 6. Write Trailer
-    // We can reuse IndexWriter::flush logic partially? 
-    // Or just write it manually since we have the buffers.
-    
-    main_buf.align(16)?;
-    
-    let mut name_f = name_buf.finish()?;
-    let n = io::copy(&mut name_f, &mut main_buf.writer)?;
-    main_buf.offset += n;
-    main_buf.align(16)?;
-    
-    let post_off = main_buf.offset();
-    let mut post_f = post_buf.finish()?;
-    let n = io::copy(&mut post_f, &mut main_buf.writer)?;
-    main_buf.offset += n;
-    main_buf.align(16)?;
+        w.flush()
+    }
     
-    let name_idx_off = main_buf.offset();
-    let mut name_idx_f = name_index_buf.finish()?;
-    let n = io::copy(&mut name_idx_f, &mut main_buf.writer)?;
-    main_buf.offset += n;
-    main_buf.align(16)?;
+    fn finish(self, main_buf: &mut IndexBuffer, roots_off: u64, roots_count: usize) -> io::Result<()> {
+        let name_off = main_buf.start_section();
+        let name_f = self.name.finish()?;
+        // Kept mapped to build the name postings from
+        let names = unsafe { Mmap::map(&name_f)? };
+        main_buf.copy_from(name_f)?;
+        main_buf.align(16)?;
+        
+        let post_off = main_buf.start_section();
+        let post_f = self.post.finish()?;
+        main_buf.copy_from(post_f)?;
+        main_buf.align(16)?;
+        
+        let name_idx_off = main_buf.start_section();
+        let name_idx_f = self.name_index.finish()?;
+        main_buf.copy_from(name_idx_f)?;
+        main_buf.align(16)?;
+        
+        let post_idx_off = main_buf.start_section();
+        let post_idx_f = self.post_index.finish()?;
+        main_buf.copy_from(post_idx_f)?;
+        main_buf.align(16)?;
+        
+        let meta_off = main_buf.start_section();
+        let meta_f = self.meta.finish()?;
+        main_buf.copy_from(meta_f)?;
+        
+        let name_post_off = main_buf.start_section();
+        let mut name_post_index = IndexBuffer::new("")?;
+        let name_trigram_count = write_name_postings(&names, self.name_count as usize, main_buf, &mut name_post_index)?;
+        main_buf.align(16)?;
+        
+        let name_post_idx_off = main_buf.start_section();
+        main_buf.copy_from(name_post_index.finish()?)?;
+        
+        main_buf.write_trailer(&[
+            roots_off,
+            roots_count as u64,
+            name_off,
+            self.name_count as u64,
+            post_off,
+            self.trigram_count as u64,
+            name_idx_off,
+            post_idx_off,
+            meta_off,
+            name_post_off,
+            name_trigram_count as u64,
+            name_post_idx_off,
+        ])?;
+        
+        main_buf.publish()?;
+        
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::read::{trigram_u32, PostReader};
+    use crate::index::write::write_test_index;
     
-    let post_idx_off = main_buf.offset();
-    let mut post_idx_f = post_index_buf.finish()?;
-    let n = io::copy(&mut post_idx_f, &mut main_buf.writer)?;
-    main_buf.offset += n;
+    fn ids(ix: &Index, trigram: &str) -> Vec<usize> {
+        let mut r = PostReader::new(ix, trigram_u32(trigram), None);
+        let mut ids = Vec::new();
+        while r.next() {
+            ids.push(r.fileid as usize);
+        }
+        ids
+    }
     
-    main_buf.write_uint64(roots_off)?;
-    main_buf.write_uint64(roots_count as u64)?;
-    main_buf.write_uint64(name_off)?;
-    main_buf.write_uint64(name_count as u64)?;
-    main_buf.write_uint64(post_off)?;
-    main_buf.write_uint64(trigram_count as u64)?;
-    main_buf.write_uint64(name_idx_off)?;
-    main_buf.write_uint64(post_idx_off)?;
-    main_buf.write_string("\ncsearch trlr 2\n")?;
+    #[test]
+    fn merge_many_shadows_and_renumbers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        write_test_index(&path("0"), &["/a", "/b/c"], &[
+            ("/a/1", "one"),
+            ("/a/x/2", "two"),
+            ("/b/c/6", "six"),
+        ]).unwrap();
+        // Replaces /a/x/2 and adds /a/x/4
+        write_test_index(&path("1"), &["/a/x"], &[
+            ("/a/x/2", "deux"),
+            ("/a/x/4", "quatre"),
+        ]).unwrap();
+        // Covers /b/c, which it doesn't have
+        write_test_index(&path("2"), &["/b"], &[
+            ("/b/5", "cinq"),
+        ]).unwrap();
+        
+        let report = merge_many(&path("m"), &[&path("0"), &path("1"), &path("2")]).unwrap();
+        assert_eq!(report.superseded_roots, vec!["/b/c"]);
+        
+        let ix = Index::open(path("m")).unwrap();
+        assert_eq!(read_roots(&ix), vec!["/a", "/a/x", "/b"]);
+        let names: Vec<String> = (0..ix.num_name).map(|id| ix.name(id)).collect();
+        assert_eq!(names, vec!["/a/1", "/a/x/2", "/a/x/4", "/b/5"]);
+        
+        // The postings and metadata follow the files to their new ids
+        for (name, content) in [("/a/1", "one"), ("/a/x/2", "deux"), ("/a/x/4", "quatre"), ("/b/5", "cinq")] {
+            let id = ix.find_name(name).unwrap();
+            assert_eq!(ids(&ix, &content[..3]), vec![id], "{}", name);
+            assert_eq!(ix.file_meta(id).unwrap().size, content.len() as u64, "{}", name);
+        }
+        // The files that were replaced or dropped are gone
+        assert!(ids(&ix, "two").is_empty());
+        assert!(ids(&ix, "six").is_empty());
+        assert!(ix.verify().is_empty());
+    }
     
-    main_buf.flush()?;
+    #[test]
+    fn merge_many_prefers_later_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        write_test_index(&path("0"), &["/r"], &[("/r/f", "old"), ("/r/g", "gee")]).unwrap();
+        write_test_index(&path("1"), &["/s"], &[("/r/f", "new")]).unwrap();
+        
+        merge_many(&path("m"), &[&path("0"), &path("1")]).unwrap();
+        let ix = Index::open(path("m")).unwrap();
+        assert_eq!(read_roots(&ix), vec!["/r", "/s"]);
+        assert_eq!(ix.num_name, 2);
+        assert_eq!(ids(&ix, "new"), vec![ix.find_name("/r/f").unwrap()]);
+        assert_eq!(ids(&ix, "gee"), vec![ix.find_name("/r/g").unwrap()]);
+        assert!(ids(&ix, "old").is_empty());
+    }
     
-    Ok(())
+    #[test]
+    fn merge_shadows_whole_path_components_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        write_test_index(&path("old"), &["/src/foo", "/src/foobar"], &[
+            ("/src/foo/a", "aaa"),
+            ("/src/foo/b", "bbb"),
+            ("/src/foobar/c", "ccc"),
+        ]).unwrap();
+        // Re-indexing /src/foo, where b was deleted
+        write_test_index(&path("new"), &["/src/foo"], &[("/src/foo/a", "AAA")]).unwrap();
+        
+        let report = merge(&path("m"), &path("old"), &path("new")).unwrap();
+        assert_eq!(report.superseded_roots, vec!["/src/foo"]);
+        let ix = Index::open(path("m")).unwrap();
+        assert_eq!(read_roots(&ix), vec!["/src/foo", "/src/foobar"]);
+        let names: Vec<String> = (0..ix.num_name).map(|id| ix.name(id)).collect();
+        assert_eq!(names, vec!["/src/foo/a", "/src/foobar/c"]);
+        assert_eq!(ids(&ix, "AAA"), vec![ix.find_name("/src/foo/a").unwrap()]);
+        assert_eq!(ids(&ix, "ccc"), vec![ix.find_name("/src/foobar/c").unwrap()]);
+        assert!(ids(&ix, "bbb").is_empty());
+        assert!(!is_shadowed("/src/foobar/c", &["/src/foo".to_string()]));
+    }
 }
@@ -2,6 +2,10 @@ pub mod write;
 pub mod regexp;
 pub mod read;
 pub mod merge;
+pub mod verify;
+pub mod lock;
+pub mod postings;
+pub mod explain;
 
 pub use write::IndexWriter;
 pub use read::Index;
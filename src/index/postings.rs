@@ -0,0 +1,471 @@
+use crate::index::read::{gallop, trigram_u32, Index, PostReader, PostingTable};
+use crate::index::regexp::{Query, QueryOp};
+
+/// A stream of increasing file ids, such as the candidates of a query.
+/// Ids are decoded as they are asked for, so a caller that stops early
+/// never reads the rest of the posting lists.
+pub trait PostingIterator {
+    /// Moves to the next file id.
+    fn next(&mut self) -> Option<u32>;
+    
+    /// Moves to the first file id not below `target`. Stays on the current
+    /// id if it is already there.
+    fn seek(&mut self, target: u32) -> Option<u32>;
+    
+    /// An upper bound on the number of ids left.
+    fn estimate(&self) -> usize;
+    
+    /// Reads all the remaining ids.
+    fn collect_ids(&mut self) -> Vec<u32> {
+        let mut ids = Vec::with_capacity(self.estimate());
+        while let Some(id) = self.next() {
+            ids.push(id);
+        }
+        ids
+    }
+}
+
+impl PostingIterator for PostReader<'_> {
+    fn next(&mut self) -> Option<u32> {
+        PostReader::next(self).then_some(self.fileid as u32)
+    }
+    
+    fn seek(&mut self, target: u32) -> Option<u32> {
+        PostReader::seek(self, target).then_some(self.fileid as u32)
+    }
+    
+    fn estimate(&self) -> usize {
+        self.max()
+    }
+}
+
+/// No files.
+pub struct EmptyIter;
+
+impl PostingIterator for EmptyIter {
+    fn next(&mut self) -> Option<u32> {
+        None
+    }
+    
+    fn seek(&mut self, _target: u32) -> Option<u32> {
+        None
+    }
+    
+    fn estimate(&self) -> usize {
+        0
+    }
+}
+
+/// Every file id below `end`.
+pub struct AllIter {
+    cur: Option<u32>,
+    end: u32,
+}
+
+impl AllIter {
+    pub fn new(end: u32) -> Self {
+        AllIter { cur: None, end }
+    }
+}
+
+impl PostingIterator for AllIter {
+    fn next(&mut self) -> Option<u32> {
+        let id = self.cur.map_or(0, |c| c + 1);
+        self.cur = Some(id.min(self.end));
+        (id < self.end).then_some(id)
+    }
+    
+    fn seek(&mut self, target: u32) -> Option<u32> {
+        let id = self.cur.map_or(target, |c| c.max(target));
+        self.cur = Some(id.min(self.end));
+        (id < self.end).then_some(id)
+    }
+    
+    fn estimate(&self) -> usize {
+        (self.end - self.cur.map_or(0, |c| c + 1).min(self.end)) as usize
+    }
+}
+
+/// The ids of a sorted list, such as the files of a directory.
+pub struct ListIter {
+    list: Vec<u32>,
+    // One past the current id
+    pos: usize,
+}
+
+impl ListIter {
+    pub fn new(list: Vec<u32>) -> Self {
+        ListIter { list, pos: 0 }
+    }
+}
+
+impl PostingIterator for ListIter {
+    fn next(&mut self) -> Option<u32> {
+        let id = *self.list.get(self.pos)?;
+        self.pos += 1;
+        Some(id)
+    }
+    
+    fn seek(&mut self, target: u32) -> Option<u32> {
+        if self.pos > 0 && self.list[self.pos - 1] >= target {
+            return Some(self.list[self.pos - 1]);
+        }
+        self.pos = gallop(&self.list, self.pos, target);
+        self.next()
+    }
+    
+    fn estimate(&self) -> usize {
+        self.list.len() - self.pos
+    }
+}
+
+/// The ids found in all of its iterators. The first one, the rarest, leads
+/// and the others seek to it.
+pub struct AndIter<'a> {
+    its: Vec<Box<dyn PostingIterator + 'a>>,
+}
+
+impl<'a> AndIter<'a> {
+    pub fn new(mut its: Vec<Box<dyn PostingIterator + 'a>>) -> Self {
+        assert!(!its.is_empty(), "AND of no iterators");
+        its.sort_by_key(|it| it.estimate());
+        AndIter { its }
+    }
+    
+    /// Moves every iterator to the first id not below `id` that they all
+    /// hold.
+    fn align(&mut self, mut id: u32) -> Option<u32> {
+        'agree: loop {
+            for i in 1..self.its.len() {
+                let found = self.its[i].seek(id)?;
+                if found > id {
+                    id = self.its[0].seek(found)?;
+                    continue 'agree;
+                }
+            }
+            return Some(id);
+        }
+    }
+}
+
+impl PostingIterator for AndIter<'_> {
+    fn next(&mut self) -> Option<u32> {
+        let id = self.its[0].next()?;
+        self.align(id)
+    }
+    
+    fn seek(&mut self, target: u32) -> Option<u32> {
+        let id = self.its[0].seek(target)?;
+        self.align(id)
+    }
+    
+    fn estimate(&self) -> usize {
+        self.its.iter().map(|it| it.estimate()).min().unwrap_or(0)
+    }
+}
+
+/// The ids found in any of its iterators.
+pub struct OrIter<'a> {
+    its: Vec<Box<dyn PostingIterator + 'a>>,
+    // The current id of each iterator, None once it is exhausted
+    heads: Vec<Option<u32>>,
+    started: bool,
+    cur: Option<u32>,
+}
+
+impl<'a> OrIter<'a> {
+    pub fn new(its: Vec<Box<dyn PostingIterator + 'a>>) -> Self {
+        let heads = vec![None; its.len()];
+        OrIter { its, heads, started: false, cur: None }
+    }
+}
+
+impl PostingIterator for OrIter<'_> {
+    fn next(&mut self) -> Option<u32> {
+        for (it, head) in self.its.iter_mut().zip(self.heads.iter_mut()) {
+            if !self.started || (head.is_some() && *head == self.cur) {
+                *head = it.next();
+            }
+        }
+        self.started = true;
+        self.cur = self.heads.iter().flatten().min().copied();
+        self.cur
+    }
+    
+    fn seek(&mut self, target: u32) -> Option<u32> {
+        if let Some(cur) = self.cur
+            && cur >= target {
+            return Some(cur);
+        }
+        for (it, head) in self.its.iter_mut().zip(self.heads.iter_mut()) {
+            if !self.started || head.is_some_and(|h| h < target) {
+                *head = it.seek(target);
+            }
+        }
+        self.started = true;
+        self.cur = self.heads.iter().flatten().min().copied();
+        self.cur
+    }
+    
+    fn estimate(&self) -> usize {
+        self.its.iter().fold(0, |sum, it| sum.saturating_add(it.estimate()))
+    }
+}
+
+/// Relative costs of decoding one posting, of checking one candidate file
+/// against the regexp, which means reading it, and of checking one
+/// candidate name, which is already in memory.
+const DECODE_COST: usize = 1;
+pub const VERIFY_COST: usize = 1000;
+pub const NAME_VERIFY_COST: usize = 8;
+
+/// Whether reading `count` postings to filter `candidates` files, out of
+/// `total`, can save more than it costs: the list rules out at most the
+/// files it doesn't hold, each of which costs `verify_cost` to check.
+fn worth_reading(candidates: usize, count: usize, total: usize, verify_cost: usize) -> bool {
+    let saved = candidates.min(total.saturating_sub(count)).saturating_mul(verify_cost);
+    saved > candidates.min(count).saturating_mul(DECODE_COST)
+}
+
+/// An operand of an AND or OR query.
+enum Operand<'q> {
+    Trigram(u32),
+    Sub(&'q Query),
+}
+
+impl Index {
+    /// Returns the files that may match `q` according to the posting lists
+    /// `table`, only among `restrict` when given, which must be sorted.
+    /// The ids are decoded lazily. The result may hold files that don't
+    /// match: AND operands are applied rarest first and dropped once
+    /// reading them costs more than checking the files they could rule
+    /// out, and an OR that would cost too much matches every file. Checking
+    /// a file costs `verify_cost`, `VERIFY_COST` for the contents of a file
+    /// and `NAME_VERIFY_COST` for its name.
+    pub fn query_postings(&self, table: &PostingTable, q: &Query, restrict: Option<Vec<u32>>, verify_cost: usize) -> Box<dyn PostingIterator + '_> {
+        let candidates = restrict.as_ref().map_or(self.num_name, |r| r.len());
+        let plan = self.plan(table, q, candidates, verify_cost);
+        match (restrict, plan) {
+            (None, None) => Box::new(AllIter::new(self.num_name as u32)),
+            (Some(r), None) => Box::new(ListIter::new(r)),
+            (None, Some(it)) => it,
+            (Some(r), Some(it)) => Box::new(AndIter::new(vec![Box::new(ListIter::new(r)), it])),
+        }
+    }
+    
+    /// Like `query_postings` over the contents of the files.
+    pub fn posting_iter(&self, q: &Query, restrict: Option<Vec<u32>>) -> Box<dyn PostingIterator + '_> {
+        self.query_postings(&self.content_postings(), q, restrict, VERIFY_COST)
+    }
+    
+    pub fn posting_query(&self, q: &Query) -> Vec<u32> {
+        self.posting_iter(q, None).collect_ids()
+    }
+    
+    /// Like `posting_query`, but only considers the files in `restrict`,
+    /// which must be sorted.
+    pub fn posting_query_restricted(&self, q: &Query, restrict: Vec<u32>) -> Vec<u32> {
+        self.posting_iter(q, Some(restrict)).collect_ids()
+    }
+    
+    /// Returns the files whose names may match `q`, using the trigrams of
+    /// the names. Indexes without name postings return all files.
+    pub fn name_query(&self, q: &Query) -> Vec<u32> {
+        match &self.name_postings {
+            Some(table) => self.query_postings(table, q, None, NAME_VERIFY_COST).collect_ids(),
+            None => (0..self.num_name as u32).collect(),
+        }
+    }
+    
+    /// An upper bound on the number of files that match `q`, out of `n`,
+    /// from the counts of its posting lists.
+    pub fn estimate(&self, table: &PostingTable, q: &Query, n: usize) -> usize {
+        match q.op {
+            QueryOp::None => 0,
+            QueryOp::All => n,
+            QueryOp::And => self.operands(table, q, n).into_iter().fold(n, |m, (count, _)| m.min(count)),
+            QueryOp::Or => self.operands(table, q, n).into_iter()
+                .fold(0, |sum: usize, (count, _)| sum.saturating_add(count)).min(n),
+        }
+    }
+    
+    /// The trigrams and subqueries of `q` with their estimated counts,
+    /// rarest first.
+    fn operands<'q>(&self, table: &PostingTable, q: &'q Query, n: usize) -> Vec<(usize, Operand<'q>)> {
+        let mut operands: Vec<(usize, Operand)> = q.trigram.iter()
+            .map(|t| {
+                let tri = trigram_u32(t);
+                (self.list_count(table, tri), Operand::Trigram(tri))
+            })
+            .chain(q.sub.iter().map(|sub| (self.estimate(table, sub, n), Operand::Sub(sub))))
+            .collect();
+        operands.sort_by_key(|&(count, _)| count);
+        operands
+    }
+    
+    /// Builds the iterator for `q` when about `candidates` files are left,
+    /// or None when it is cheaper to check every file, at `verify_cost`
+    /// each.
+    fn plan(&self, table: &PostingTable, q: &Query, candidates: usize, verify_cost: usize) -> Option<Box<dyn PostingIterator + '_>> {
+        match q.op {
+            QueryOp::None => Some(Box::new(EmptyIter)),
+            QueryOp::All => None,
+            QueryOp::And => {
+                let mut its: Vec<Box<dyn PostingIterator>> = Vec::new();
+                let mut candidates = candidates;
+                for (count, operand) in self.operands(table, q, candidates) {
+                    if count == 0 {
+                        return Some(Box::new(EmptyIter));
+                    }
+                    // Every later operand is at least as common
+                    if !worth_reading(candidates, count, self.num_name, verify_cost) {
+                        break;
+                    }
+                    let it: Option<Box<dyn PostingIterator>> = match operand {
+                        Operand::Trigram(tri) => Some(Box::new(PostReader::with_table(self, table, tri, None))),
+                        Operand::Sub(sub) => self.plan(table, sub, candidates, verify_cost),
+                    };
+                    if let Some(it) = it {
+                        its.push(it);
+                        candidates = candidates.min(count);
+                    }
+                }
+                match its.len() {
+                    0 => None,
+                    1 => its.pop(),
+                    _ => Some(Box::new(AndIter::new(its))),
+                }
+            }
+            QueryOp::Or => {
+                let operands = self.operands(table, q, candidates);
+                if operands.is_empty() {
+                    return Some(Box::new(EmptyIter));
+                }
+                let total = operands.iter().fold(0, |sum: usize, &(count, _)| sum.saturating_add(count));
+                if candidates.saturating_mul(verify_cost) <= total.saturating_mul(DECODE_COST) {
+                    return None;
+                }
+                let mut its: Vec<Box<dyn PostingIterator>> = Vec::new();
+                for (_, operand) in operands {
+                    match operand {
+                        Operand::Trigram(tri) => its.push(Box::new(PostReader::with_table(self, table, tri, None))),
+                        // A branch that matches everything makes the whole OR do
+                        Operand::Sub(sub) => its.push(self.plan(table, sub, candidates, verify_cost)?),
+                    }
+                }
+                if its.len() == 1 {
+                    return its.pop();
+                }
+                Some(Box::new(OrIter::new(its)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn list(ids: &[u32]) -> Box<dyn PostingIterator> {
+        Box::new(ListIter::new(ids.to_vec()))
+    }
+    
+    #[test]
+    fn empty_iter_has_nothing() {
+        let mut it = EmptyIter;
+        assert_eq!(it.seek(0), None);
+        assert_eq!(it.next(), None);
+        assert_eq!(it.estimate(), 0);
+    }
+    
+    #[test]
+    fn all_iter_seek() {
+        let mut it = AllIter::new(10);
+        assert_eq!(it.seek(3), Some(3));
+        // Stays on the current id
+        assert_eq!(it.seek(3), Some(3));
+        assert_eq!(it.seek(1), Some(3));
+        assert_eq!(it.next(), Some(4));
+        assert_eq!(it.seek(9), Some(9));
+        assert_eq!(it.estimate(), 0);
+        assert_eq!(it.next(), None);
+        
+        let mut it = AllIter::new(10);
+        assert_eq!(it.seek(10), None);
+        assert_eq!(it.next(), None);
+        assert_eq!(it.seek(3), None);
+        assert_eq!(AllIter::new(0).next(), None);
+    }
+    
+    #[test]
+    fn and_iter_seek() {
+        let and = || AndIter::new(vec![
+            list(&[1, 3, 5, 7, 9, 11, 13]),
+            list(&[3, 4, 5, 9, 11, 12, 13]),
+            list(&[0, 3, 9, 11, 13]),
+        ]);
+        let mut it = and();
+        assert_eq!(it.next(), Some(3));
+        assert_eq!(it.seek(4), Some(9));
+        assert_eq!(it.seek(9), Some(9));
+        assert_eq!(it.seek(2), Some(9));
+        assert_eq!(it.next(), Some(11));
+        assert_eq!(it.seek(12), Some(13));
+        assert_eq!(it.next(), None);
+        
+        let mut it = and();
+        assert_eq!(it.seek(10), Some(11));
+        assert_eq!(it.collect_ids(), vec![13]);
+        
+        let mut it = and();
+        assert_eq!(it.seek(14), None);
+        assert_eq!(it.next(), None);
+        
+        let mut it = AndIter::new(vec![list(&[1, 2]), list(&[3, 4])]);
+        assert_eq!(it.next(), None);
+    }
+    
+    #[test]
+    fn or_iter_seek() {
+        let or = || OrIter::new(vec![list(&[1, 5, 9]), list(&[2, 5, 20]), Box::new(EmptyIter)]);
+        let mut it = or();
+        assert_eq!(it.next(), Some(1));
+        assert_eq!(it.seek(2), Some(2));
+        assert_eq!(it.seek(2), Some(2));
+        assert_eq!(it.seek(0), Some(2));
+        // Ids in several lists come once
+        assert_eq!(it.next(), Some(5));
+        assert_eq!(it.next(), Some(9));
+        assert_eq!(it.seek(10), Some(20));
+        assert_eq!(it.next(), None);
+        
+        let mut it = or();
+        assert_eq!(it.seek(6), Some(9));
+        assert_eq!(it.collect_ids(), vec![20]);
+        
+        let mut it = or();
+        assert_eq!(it.seek(21), None);
+        assert_eq!(it.next(), None);
+        assert_eq!(it.seek(1), None);
+    }
+    
+    #[test]
+    fn name_lists_are_skipped_sooner() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("ix").to_string_lossy().into_owned();
+        // "abc" is in the names and contents of all files but one
+        let mut names: Vec<String> = (1..1000).map(|i| format!("/r/abc{:04}", i)).collect();
+        names.push("/r/xyz".to_string());
+        let files: Vec<(&str, &str)> = names.iter().map(|name| (name.as_str(), name.as_str())).collect();
+        crate::index::write::write_test_index(&file, &["/r"], &files).unwrap();
+        let ix = Index::open(&file).unwrap();
+        
+        // Ruling out one file is worth reading the list when that saves
+        // reading the file, but not when it saves checking its name
+        let abc = Query::all().and_trigrams(vec!["abc".to_string()]);
+        assert_eq!(ix.posting_query(&abc).len(), 999);
+        assert_eq!(ix.name_query(&abc).len(), 1000);
+        let xyz = Query::all().and_trigrams(vec!["xyz".to_string()]);
+        assert_eq!(ix.name_query(&xyz), vec![999]);
+    }
+}
@@ -3,7 +3,10 @@ use std::fs::File;
 use std::path::Path as StdPath;
 use std::io;
 use std::str;
-use crate::index::regexp::{Query, QueryOp};
+use crate::index::write::{cmp_paths, path_within, skip_count, FileMeta, FILE_META_SIZE, SKIP_ENTRY_SIZE, SKIP_INTERVAL};
+use xxhash_rust::xxh64::xxh64;
+use std::cmp::Ordering;
+use std::ops::Range;
 use byteorder::{BigEndian, ByteOrder};
 
 // Helper function to read 24-bit big-endian integer
@@ -16,12 +19,27 @@ fn read_u24_be(buf: &[u8]) -> u32 {
 
 // Constants
 const TRAILER_MAGIC_V2: &str = "\ncsearch trlr 2\n";
+const TRAILER_MAGIC_V3: &str = "\ncsearch trlr 3\n";
+const TRAILER_MAGIC_V4: &str = "\ncsearch trlr 4\n";
+const TRAILER_MAGIC_V5: &str = "\ncsearch trlr 5\n";
+const TRAILER_MAGIC_V6: &str = "\ncsearch trlr 6\n";
 const POST_BLOCK_SIZE: usize = 256;
 const NAME_GROUP_SIZE: usize = 16;
 const DELTA_ZERO_ENC: u32 = 16;
 
+/// The location of a set of posting lists and of the posting index that
+/// finds them by trigram.
+#[derive(Debug, Clone, Copy)]
+pub struct PostingTable {
+    pub data: usize,
+    pub num_post: usize,
+    pub index: usize,
+    pub num_block: usize,
+}
+
 pub struct Index {
     pub mmap: Mmap,
+    pub version: u32,
     
     // Offsets/Counts
     pub path_data: usize,
@@ -33,6 +51,16 @@ pub struct Index {
     pub name_index: usize,
     pub post_index: usize,
     pub num_post_block: usize,
+    /// Start of the per-file metadata section (format 3 and later).
+    pub meta_data: Option<usize>,
+    /// Posting lists over the trigrams of the file names (format 5 and later).
+    pub name_postings: Option<PostingTable>,
+    /// Start of the trailer.
+    pub trailer: usize,
+    /// Checksums of the sections in file order (format 4 and later).
+    pub checksums: Option<Vec<u64>>,
+    /// Name and version of the tool that wrote the index (format 4 and later).
+    pub tool_version: Option<String>,
 }
 
 impl Index {
@@ -46,51 +74,99 @@ impl Index {
         
         let trailer_len = TRAILER_MAGIC_V2.len();
         let magic_start = mmap.len() - trailer_len;
-        if &mmap[magic_start..] != TRAILER_MAGIC_V2.as_bytes() {
-             return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid trailer magic"));
-        }
+        // Offsets and counts, then from format 4 the section checksums and
+        // a checksum of the trailer
+        let (version, num_fields, num_sums) = match &mmap[magic_start..] {
+            m if m == TRAILER_MAGIC_V2.as_bytes() => (2, 8, 0),
+            m if m == TRAILER_MAGIC_V3.as_bytes() => (3, 9, 0),
+            m if m == TRAILER_MAGIC_V4.as_bytes() => (4, 9, 7),
+            m if m == TRAILER_MAGIC_V5.as_bytes() => (5, 12, 9),
+            m if m == TRAILER_MAGIC_V6.as_bytes() => (6, 12, 9),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid trailer magic")),
+        };
+        let trailer_words = if num_sums > 0 { num_fields + num_sums + 1 } else { num_fields };
         
-        let n = magic_start as isize - 8 * 8;
-        if n < 0 {
+        let Some(n) = magic_start.checked_sub(trailer_words * 8) else {
              return Err(io::Error::new(io::ErrorKind::InvalidData, "file too short for trailer"));
-        }
-        let n = n as usize;
-        
-        // Ensure we have enough data to read all the trailer fields
-        if n + 64 > mmap.len() {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid trailer size"));
-        }
-        
-        let path_data = BigEndian::read_u64(&mmap[n..n+8]) as usize;
-        let num_path = BigEndian::read_u64(&mmap[n+8..n+16]) as usize;
-        let name_data = BigEndian::read_u64(&mmap[n+16..n+24]) as usize;
-        let num_name = BigEndian::read_u64(&mmap[n+24..n+32]) as usize;
-        let post_data = BigEndian::read_u64(&mmap[n+32..n+40]) as usize;
-        let num_post = BigEndian::read_u64(&mmap[n+40..n+48]) as usize;
-        let name_index = BigEndian::read_u64(&mmap[n+48..n+56]) as usize;
-        let post_index = BigEndian::read_u64(&mmap[n+56..n+64]) as usize;
+        };
+        let field = |i: usize| BigEndian::read_u64(&mmap[n + i * 8..n + i * 8 + 8]) as usize;
+        
+        let path_data = field(0);
+        let num_path = field(1);
+        let name_data = field(2);
+        let num_name = field(3);
+        let post_data = field(4);
+        let num_post = field(5);
+        let name_index = field(6);
+        let post_index = field(7);
+        let meta_data = if version >= 3 {
+            Some(field(8))
+        } else {
+            None
+        };
+        let name_post = if version >= 5 {
+            Some((field(9), field(10), field(11)))
+        } else {
+            None
+        };
+        let checksums = if num_sums > 0 {
+            let sums_off = n + num_fields * 8;
+            let sums_end = sums_off + num_sums * 8;
+            if BigEndian::read_u64(&mmap[sums_end..sums_end + 8]) != xxh64(&mmap[n..sums_end], 0) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "trailer checksum mismatch"));
+            }
+            Some((0..num_sums).map(|i| BigEndian::read_u64(&mmap[sums_off + i * 8..sums_off + i * 8 + 8])).collect())
+        } else {
+            None
+        };
+        // The post index runs up to the metadata section, or to the trailer.
+        // The metadata runs up to the name postings, or to the trailer.
+        let post_index_end = meta_data.unwrap_or(n);
+        let meta_end = name_post.map_or(n, |(data, _, _)| data);
         
         // Validate offsets are within file bounds
         if path_data >= mmap.len() || name_data >= mmap.len() || 
            post_data >= mmap.len() || name_index >= mmap.len() || 
-           post_index >= mmap.len() {
+           post_index >= mmap.len() || post_index_end > n || meta_end > n {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid section offsets"));
         }
         
-        // Validate ordering: path_data <= name_data <= post_data <= name_index <= post_index
+        // Validate ordering: path_data <= name_data <= post_data <= name_index <= post_index <= meta_data
+        // <= name_post_data <= name_post_index
         if path_data > name_data || name_data > post_data || 
-           post_data > name_index || name_index > post_index {
+           post_data > name_index || name_index > post_index || post_index > post_index_end ||
+           post_index_end > meta_end {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid section ordering"));
         }
+        let name_postings = match name_post {
+            Some((data, num_post, index)) if meta_end <= data && data <= index && index <= n => Some(PostingTable {
+                data,
+                num_post,
+                index,
+                num_block: (n - index) / POST_BLOCK_SIZE,
+            }),
+            Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid section ordering")),
+            None => None,
+        };
         
-        let num_post_block = if post_index <= n {
-            (n - post_index) / POST_BLOCK_SIZE
+        let num_post_block = (post_index_end - post_index) / POST_BLOCK_SIZE;
+        
+        // The header line after the magic names the tool that wrote the index
+        let tool_version = if version >= 4 {
+            let header = &mmap[..path_data];
+            let magic = format!("csearch index {}\n", version);
+            let Some(line) = header.strip_prefix(magic.as_bytes()) else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid header magic"));
+            };
+            let line = line.split(|&b| b == b'\n').next().unwrap_or_default();
+            Some(String::from_utf8_lossy(line).into_owned())
         } else {
-            0
+            None
         };
-
+        
         Ok(Index {
             mmap,
+            version,
             path_data,
             num_path,
             name_data,
@@ -100,9 +176,24 @@ impl Index {
             name_index,
             post_index,
             num_post_block,
+            meta_data,
+            name_postings,
+            trailer: n,
+            checksums,
+            tool_version,
         })
     }
     
+    /// The posting lists of the file contents.
+    pub fn content_postings(&self) -> PostingTable {
+        PostingTable {
+            data: self.post_data,
+            num_post: self.num_post,
+            index: self.post_index,
+            num_block: self.num_post_block,
+        }
+    }
+    
     fn slice_from(&self, off: usize) -> &[u8] {
         &self.mmap[off..]
     }
@@ -119,7 +210,117 @@ impl Index {
          r.next().unwrap_or_default()
     }
     
+    /// Returns the recorded metadata of a file, if the index has any.
+    pub fn file_meta(&self, fileid: usize) -> Option<FileMeta> {
+        let meta = self.meta_data?;
+        let off = meta + fileid * FILE_META_SIZE;
+        if fileid >= self.num_name || off + FILE_META_SIZE > self.mmap.len() {
+            return None;
+        }
+        Some(FileMeta::decode(&self.mmap[off..off + FILE_META_SIZE]))
+    }
+    
+    /// Looks up the file id of `name` by binary searching the first
+    /// name of each group in the name index. Names are only guaranteed to
+    /// be in index path order since format 3; older indexes are scanned.
+    pub fn find_name(&self, name: &str) -> Option<usize> {
+        if self.version < 3 {
+            let mut r = self.names_at(0, self.num_name);
+            let mut id = 0;
+            while let Some(p) = r.next() {
+                if p == name {
+                    return Some(id);
+                }
+                id += 1;
+            }
+            return None;
+        }
+        
+        let num_group = self.num_name.div_ceil(NAME_GROUP_SIZE);
+        let mut i = 0;
+        let mut j = num_group;
+        while i < j {
+            let h = i + (j - i) / 2;
+            let head = self.name(h * NAME_GROUP_SIZE);
+            if cmp_paths(&head, name) == Ordering::Greater {
+                j = h;
+            } else {
+                i = h + 1;
+            }
+        }
+        if i == 0 {
+            return None;
+        }
+        
+        let min = (i - 1) * NAME_GROUP_SIZE;
+        let mut r = self.names_at(min, min + NAME_GROUP_SIZE);
+        let mut id = min;
+        while let Some(p) = r.next() {
+            if p == name {
+                return Some(id);
+            }
+            id += 1;
+        }
+        None
+    }
+    
+    /// Returns the ids of the files within the directory `dir`. Names are
+    /// in index path order, where the files below a directory sort right
+    /// after it, so the ids form a range found by binary search. Older
+    /// indexes are scanned.
+    pub fn name_range(&self, dir: &str) -> Range<usize> {
+        if self.version < 3 {
+            let mut r = self.names_at(0, self.num_name);
+            let mut range: Option<Range<usize>> = None;
+            let mut id = 0;
+            while let Some(p) = r.next() {
+                if path_within(&p, dir) {
+                    let start = range.map_or(id, |r| r.start);
+                    range = Some(start..id + 1);
+                }
+                id += 1;
+            }
+            return range.unwrap_or(0..0);
+        }
+        
+        let start = self.partition_names(|n| cmp_paths(n, dir) == Ordering::Less);
+        let end = self.partition_names(|n| cmp_paths(n, dir) == Ordering::Less || path_within(n, dir));
+        start..end.max(start)
+    }
+    
+    /// Returns the first file id whose name does not satisfy `pred`, which
+    /// must hold for all names before that one and none after it.
+    fn partition_names<F: Fn(&str) -> bool>(&self, pred: F) -> usize {
+        let num_group = self.num_name.div_ceil(NAME_GROUP_SIZE);
+        let mut i = 0;
+        let mut j = num_group;
+        while i < j {
+            let h = i + (j - i) / 2;
+            if pred(&self.name(h * NAME_GROUP_SIZE)) {
+                i = h + 1;
+            } else {
+                j = h;
+            }
+        }
+        if i == 0 {
+            return 0;
+        }
+        
+        let min = (i - 1) * NAME_GROUP_SIZE;
+        let mut r = self.names_at(min, min + NAME_GROUP_SIZE);
+        let mut id = min;
+        while let Some(p) = r.next() {
+            if !pred(&p) {
+                break;
+            }
+            id += 1;
+        }
+        id
+    }
+    
     pub fn names_at(&self, min: usize, max: usize) -> PathReader<'_> {
+        // The name data of the last group is followed by other sections
+        let max = max.min(self.num_name);
         if min >= self.num_name || max <= min {
             return PathReader::new(&[], 0);
         }
@@ -157,7 +358,7 @@ impl Index {
     pub fn roots(&self) -> PathReader<'_> {
         self.roots_at(0, self.num_path)
     }
-
+    
     pub fn roots_at(&self, min: usize, max: usize) -> PathReader<'_> {
         if min >= self.num_path || max <= min {
             return PathReader::new(&[], 0);
@@ -182,135 +383,35 @@ impl Index {
         }
         r
     }
-
-    pub fn post_map_iter(&self) -> PostMapIter<'_> {
-        PostMapIter::new(self)
-    }
-    
-    pub fn posting_query(&self, q: &Query) -> Vec<u32> {
-        self.posting_query_rec(q, None)
-    }
-    
-    fn posting_query_rec(&self, q: &Query, restrict: Option<Vec<u32>>) -> Vec<u32> {
-        match q.op {
-            QueryOp::None => Vec::new(),
-            QueryOp::All => {
-                if let Some(r) = restrict {
-                    return r;
-                }
-                (0..self.num_name as u32).collect()
-            }
-            QueryOp::And => {
-                let mut list = None;
-                for t in &q.trigram {
-                    let tri = trigram_u32(t);
-                    if list.is_none() {
-                        list = Some(self.posting_list(tri, restrict.clone()));
-                    } else {
-                        list = Some(self.posting_and(list.unwrap(), tri, restrict.clone()));
-                    }
-                    if list.as_ref().unwrap().is_empty() {
-                        return Vec::new();
-                    }
-                }
-                
-                let mut current_list = list;
-                
-                for sub in &q.sub {
-                    let base = if current_list.is_none() { restrict.clone() } else { current_list.clone() };
-                    current_list = Some(self.posting_query_rec(sub, base));
-                    if current_list.as_ref().unwrap().is_empty() {
-                         return Vec::new();
-                    }
-                }
-                current_list.unwrap_or_else(|| {
-                    if let Some(r) = restrict { r } else { (0..self.num_name as u32).collect() }
-                })
-            }
-            QueryOp::Or => {
-                 let mut list = None;
-                 for t in &q.trigram {
-                     let tri = trigram_u32(t);
-                     if list.is_none() {
-                         list = Some(self.posting_list(tri, restrict.clone()));
-                     } else {
-                         list = Some(self.posting_or(list.unwrap(), tri, restrict.clone()));
-                     }
-                 }
-                 
-                 let mut current_list = list.unwrap_or_default();
-                 
-                 for sub in &q.sub {
-                     let list1 = self.posting_query_rec(sub, restrict.clone());
-                     current_list = merge_or(current_list, list1);
-                 }
-                 current_list
-            }
-        }
-    }
     
-    fn posting_list(&self, trigram: u32, restrict: Option<Vec<u32>>) -> Vec<u32> {
-        let mut r = PostReader::new(self, trigram, restrict);
-        let mut x = Vec::with_capacity(r.max());
-        while r.next() {
-            x.push(r.fileid as u32);
-        }
-        x
+    pub fn post_map_iter(&self) -> PostMapIter<'_> {
+        PostMapIter::new(self, self.content_postings())
     }
     
-    fn posting_and(&self, list: Vec<u32>, trigram: u32, restrict: Option<Vec<u32>>) -> Vec<u32> {
-        let mut r = PostReader::new(self, trigram, restrict);
-        let mut x = Vec::new(); // Upper bound is list.len()
-        let mut i = 0;
-        while r.next() {
-            let fileid = r.fileid as u32;
-            while i < list.len() && list[i] < fileid {
-                i += 1;
-            }
-            if i < list.len() && list[i] == fileid {
-                x.push(fileid);
-                i += 1;
-            }
-        }
-        x
+    /// Iterates over the entries of the posting index of `table`.
+    pub fn table_iter(&self, table: PostingTable) -> PostMapIter<'_> {
+        PostMapIter::new(self, table)
     }
     
-    fn posting_or(&self, list: Vec<u32>, trigram: u32, restrict: Option<Vec<u32>>) -> Vec<u32> {
-         let mut r = PostReader::new(self, trigram, restrict);
-         let mut x = Vec::with_capacity(list.len() + r.max());
-         let mut i = 0;
-         while r.next() {
-             let fileid = r.fileid as u32;
-             while i < list.len() && list[i] < fileid {
-                 x.push(list[i]);
-                 i += 1;
-             }
-             x.push(fileid);
-             if i < list.len() && list[i] == fileid {
-                 i += 1;
-             }
-         }
-         while i < list.len() {
-             x.push(list[i]);
-             i += 1;
-         }
-         x
+    /// Number of files in the list of `trigram` in `table`.
+    pub fn list_count(&self, table: &PostingTable, trigram: u32) -> usize {
+        self.find_list_v2(table, trigram).0
     }
     
-    fn find_list_v2(&self, trigram: u32) -> (usize, usize) {
-        if self.num_post_block == 0 {
+    fn find_list_v2(&self, table: &PostingTable, trigram: u32) -> (usize, usize) {
+        if table.num_block == 0 {
             return (0, 0);
         }
         
-        let post_index_end = self.post_index + self.num_post_block * POST_BLOCK_SIZE;
+        let post_index_end = table.index + table.num_block * POST_BLOCK_SIZE;
         if post_index_end > self.mmap.len() {
             return (0, 0);
         }
         
-        let b = &self.mmap[self.post_index .. post_index_end];
+        let b = &self.mmap[table.index .. post_index_end];
         
         let mut i = 0; 
-        let mut j = self.num_post_block;
+        let mut j = table.num_block;
         while i < j {
              let h = i + (j - i) / 2;
              let off = h * POST_BLOCK_SIZE;
@@ -366,31 +467,11 @@ impl Index {
     }
 }
 
-fn trigram_u32(s: &str) -> u32 {
+pub(crate) fn trigram_u32(s: &str) -> u32 {
     let b = s.as_bytes();
     ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | (b[2] as u32)
 }
 
-fn merge_or(l1: Vec<u32>, l2: Vec<u32>) -> Vec<u32> {
-    let mut l = Vec::with_capacity(l1.len() + l2.len());
-    let mut i = 0;
-    let mut j = 0;
-    while i < l1.len() || j < l2.len() {
-        if j == l2.len() || (i < l1.len() && l1[i] < l2[j]) {
-            l.push(l1[i]);
-            i += 1;
-        } else if i == l1.len() || (j < l2.len() && l1[i] > l2[j]) {
-            l.push(l2[j]);
-            j += 1;
-        } else {
-            l.push(l1[i]);
-            i += 1;
-            j += 1;
-        }
-    }
-    l
-}
-
 // Helpers
 
 fn read_uvarint(buf: &[u8]) -> (u64, usize) {
@@ -426,6 +507,11 @@ impl<'a> PathReader<'a> {
         }
     }
     
+    /// Number of bytes after the paths read so far.
+    pub fn remaining(&self) -> usize {
+        self.data.len()
+    }
+    
     pub fn next(&mut self) -> Option<String> {
         if self.limit == 0 { return None; }
         self.limit -= 1;
@@ -461,6 +547,7 @@ impl<'a> PathReader<'a> {
 
 pub struct PostMapIter<'a> {
     ix: &'a Index,
+    table: PostingTable,
     block: &'a [u8],
     next_block: usize,
     tri_num: usize,
@@ -468,9 +555,10 @@ pub struct PostMapIter<'a> {
 }
 
 impl<'a> PostMapIter<'a> {
-    fn new(ix: &'a Index) -> Self {
+    fn new(ix: &'a Index, table: PostingTable) -> Self {
         PostMapIter {
             ix,
+            table,
             block: &[],
             next_block: 0,
             tri_num: 0,
@@ -480,17 +568,17 @@ impl<'a> PostMapIter<'a> {
     
     // Returns (trigram, count, offset)
     pub fn next(&mut self) -> Option<(u32, usize, usize)> {
-        if self.tri_num >= self.ix.num_post {
+        if self.tri_num >= self.table.num_post {
             return None;
         }
         
         self.tri_num += 1;
         
         if self.block.len() < 3 || (self.block[0] == 0 && self.block[1] == 0 && self.block[2] == 0) {
-             if self.ix.post_index + self.next_block + POST_BLOCK_SIZE > self.ix.mmap.len() {
+             if self.table.index + self.next_block + POST_BLOCK_SIZE > self.ix.mmap.len() {
                  return None;
              }
-             let start = self.ix.post_index + self.next_block;
+             let start = self.table.index + self.next_block;
              self.block = &self.ix.mmap[start .. start + POST_BLOCK_SIZE];
              self.next_block += POST_BLOCK_SIZE;
              self.file_offset = 0;
@@ -505,7 +593,7 @@ impl<'a> PostMapIter<'a> {
         let (off, n2) = read_uvarint(self.block);
         self.block = &self.block[n2..];
         
-        self.file_offset += off as usize;
+        self.file_offset = self.file_offset.wrapping_add(off as usize);
         
         Some((trigram, count as usize, self.file_offset))
     }
@@ -514,43 +602,79 @@ impl<'a> PostMapIter<'a> {
 
 // PostReader
 
+/// Returns the first position at or after `from` whose id is not below
+/// `target`, probing at doubling distances before a binary search.
+pub(crate) fn gallop(list: &[u32], from: usize, target: u32) -> usize {
+    let mut lo = from;
+    let mut step = 1;
+    while lo + step < list.len() && list[lo + step] < target {
+        lo += step;
+        step *= 2;
+    }
+    let hi = (lo + step + 1).min(list.len());
+    lo + list[lo..hi].partition_point(|&id| id < target)
+}
+
 pub struct PostReader<'a> {
     count: usize,
-    // offset: usize, // not strictly needed if we just hold the slice
+    total: usize,
     pub fileid: i32,
+    // Whether the reader moved past the last file
+    ended: bool,
     restrict: Option<Vec<u32>>,
+    // Position in `restrict` of the first id not below `fileid`
+    restrict_pos: usize,
     delta: DeltaReader<'a>,
+    // The deltas of the list, and its skip pointers (format 6 and later)
+    data: &'a [u8],
+    skips: &'a [u8],
 }
 
 impl<'a> PostReader<'a> {
     pub fn new(ix: &'a Index, trigram: u32, restrict: Option<Vec<u32>>) -> Self {
-        let (count, offset) = ix.find_list_v2(trigram);
+        Self::with_table(ix, &ix.content_postings(), trigram, restrict)
+    }
+    
+    /// Reads the list of `trigram` in the posting lists `table` of `ix`.
+    pub fn with_table(ix: &'a Index, table: &PostingTable, trigram: u32, restrict: Option<Vec<u32>>) -> Self {
+        let empty = PostReader {
+            count: 0,
+            total: 0,
+            fileid: -1,
+            ended: false,
+            restrict: None,
+            restrict_pos: 0,
+            delta: DeltaReader::new(&[]),
+            data: &[],
+            skips: &[],
+        };
+        let (count, offset) = ix.find_list_v2(table, trigram);
         if count == 0 {
-             return PostReader {
-                 count: 0,
-                 fileid: -1,
-                 restrict: None,
-                 delta: DeltaReader::new(&[]),
-             };
+            return empty;
         }
         
-        let data_start = ix.post_data + offset + 3;
+        let data_start = table.data + offset + 3;
         if data_start >= ix.mmap.len() {
-            return PostReader {
-                count: 0,
-                fileid: -1,
-                restrict: None,
-                delta: DeltaReader::new(&[]),
-            };
+            return empty;
         }
         
         let data = ix.slice_from(data_start);
+        let skip_len = if ix.version >= 6 { skip_count(count) * SKIP_ENTRY_SIZE } else { 0 };
+        let skips = match (table.data + offset).checked_sub(skip_len) {
+            Some(start) if start >= table.data => &ix.mmap[start..table.data + offset],
+            _ => &[],
+        };
         
         PostReader {
             count,
+            total: count,
             fileid: -1,
+            ended: false,
             restrict,
+            restrict_pos: 0,
             delta: DeltaReader::new(data),
+            data,
+            skips,
         }
     }
     
@@ -559,31 +683,97 @@ impl<'a> PostReader<'a> {
     }
     
     pub fn next(&mut self) -> bool {
+        self.advance() && self.match_restrict()
+    }
+    
+    /// Moves to the first file of the list at or after `target` that is
+    /// in the restriction, if any. Returns false when there is none.
+    pub fn seek(&mut self, target: u32) -> bool {
+        self.seek_unrestricted(target) && self.match_restrict()
+    }
+    
+    /// Decodes the next file id, ignoring the restriction.
+    fn advance(&mut self) -> bool {
         if self.count == 0 {
+            self.ended = true;
             return false;
         }
-        
-        while self.count > 0 {
-            self.count -= 1;
-            let d = self.delta.next();
-            if d.is_none() {
+        self.count -= 1;
+        match self.delta.next() {
+            Some(delta) => {
+                self.fileid += delta as i32;
+                true
+            }
+            None => {
                 // corrupt
-                return false; 
+                self.count = 0;
+                self.ended = true;
+                false
             }
-            let delta = d.unwrap();
-            self.fileid += delta as i32;
-            
-            if let Some(ref mut rest) = self.restrict {
-                 while !rest.is_empty() && (rest[0] as i32) < self.fileid {
-                     rest.remove(0);
-                 }
-                 if rest.is_empty() || (rest[0] as i32) != self.fileid {
-                     continue;
-                 }
+        }
+    }
+    
+    /// Moves forward until the current file is in the restriction.
+    fn match_restrict(&mut self) -> bool {
+        loop {
+            let Some(ref rest) = self.restrict else {
+                return true;
+            };
+            self.restrict_pos = gallop(rest, self.restrict_pos, self.fileid as u32);
+            if self.restrict_pos == rest.len() {
+                self.count = 0;
+                self.ended = true;
+                return false;
+            }
+            let want = rest[self.restrict_pos];
+            if want as i32 == self.fileid {
+                return true;
+            }
+            if !self.seek_unrestricted(want) {
+                return false;
             }
+        }
+    }
+    
+    fn seek_unrestricted(&mut self, target: u32) -> bool {
+        let target = target as i32;
+        if self.ended {
+            return false;
+        }
+        if self.fileid >= target {
             return true;
         }
-        false
+        // The last skip pointer before `target` that is ahead of the
+        // current position
+        let (mut k, mut hi) = (0, self.skips.len() / SKIP_ENTRY_SIZE);
+        while k < hi {
+            let mid = k + (hi - k) / 2;
+            if self.skip_fileid(mid) < target {
+                k = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let done = self.total - self.count;
+        if k > 0 && k * SKIP_INTERVAL > done {
+            let entry = &self.skips[(k - 1) * SKIP_ENTRY_SIZE..];
+            let bit = BigEndian::read_u32(&entry[4..8]) as usize;
+            if bit / 8 < self.data.len() {
+                self.fileid = self.skip_fileid(k - 1);
+                self.count = self.total - k * SKIP_INTERVAL;
+                self.delta = DeltaReader::at_bit(self.data, bit);
+            }
+        }
+        while self.fileid < target {
+            if !self.advance() {
+                return false;
+            }
+        }
+        true
+    }
+    
+    fn skip_fileid(&self, k: usize) -> i32 {
+        BigEndian::read_u32(&self.skips[k * SKIP_ENTRY_SIZE..]) as i32
     }
 }
 
@@ -600,6 +790,30 @@ impl<'a> DeltaReader<'a> {
         DeltaReader { d: data, b: 0, nb: 0 }
     }
     
+    /// Starts reading `data` at bit `bit`, as written by a skip pointer.
+    pub fn at_bit(data: &'a [u8], bit: usize) -> Self {
+        let mut r = DeltaReader::new(&data[bit / 8..]);
+        let shift = (bit % 8) as u32;
+        if shift > 0 {
+            r.b = (r.d[0] >> shift) as u64;
+            r.nb = 8 - shift;
+            r.d = &r.d[1..];
+        }
+        r
+    }
+    
+    /// Number of bits read from `data`, which must be the slice this
+    /// reader started on.
+    pub fn bit_position(&self, data: &[u8]) -> usize {
+        (data.len() - self.d.len()) * 8 - self.nb as usize
+    }
+    
+    /// Number of bytes not yet started. The rest of a partially read byte
+    /// is padding once a list is complete.
+    pub fn remaining(&self) -> usize {
+        self.d.len()
+    }
+    
     pub fn next(&mut self) -> Option<u32> {
         let i = self.next64()?;
         if i == DELTA_ZERO_ENC as u64 {
@@ -610,7 +824,7 @@ impl<'a> DeltaReader<'a> {
             Some(i as u32)
         }
     }
-
+    
     fn next64(&mut self) -> Option<u64> {
         let mut lg = 0;
         while self.b == 0 {
@@ -623,6 +837,10 @@ impl<'a> DeltaReader<'a> {
         
         let zeros = self.b.trailing_zeros();
         lg += zeros;
+        // Longer runs of zeros than any encoded value only come from corrupt data
+        if lg >= 64 {
+            return None;
+        }
         self.b >>= zeros + 1;
         self.nb -= zeros + 1;
         
@@ -647,3 +865,84 @@ impl<'a> DeltaReader<'a> {
         Some(x)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::write::write_test_index;
+    
+    /// Where `seek(target)` should land in the sorted list `ids`.
+    fn expected(ids: &[u32], target: u32) -> Option<u32> {
+        ids.iter().copied().find(|&id| id >= target)
+    }
+    
+    fn seek(r: &mut PostReader, target: u32) -> Option<u32> {
+        r.seek(target).then_some(r.fileid as u32)
+    }
+    
+    #[test]
+    fn skip_pointers_seek_like_linear_decode() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("ix").to_string_lossy().into_owned();
+        // "abc" is in two files out of three, a list long enough to get
+        // skip pointers
+        let names: Vec<String> = (0..1000).map(|i| format!("/r/f{:04}", i)).collect();
+        let files: Vec<(&str, &str)> = names.iter().enumerate()
+            .map(|(i, name)| (name.as_str(), if i % 3 == 0 { "xyz" } else { "abc" }))
+            .collect();
+        write_test_index(&file, &["/r"], &files).unwrap();
+        let ix = Index::open(&file).unwrap();
+        let abc = trigram_u32("abc");
+        
+        let mut ids = Vec::new();
+        let mut r = PostReader::new(&ix, abc, None);
+        assert!(!r.skips.is_empty());
+        while r.next() {
+            ids.push(r.fileid as u32);
+        }
+        assert_eq!(ids, (0..1000).filter(|i| i % 3 != 0).collect::<Vec<_>>());
+        
+        // From the start of the list to every target, including past its end
+        for target in 0..1010 {
+            let mut r = PostReader::new(&ix, abc, None);
+            assert_eq!(seek(&mut r, target), expected(&ids, target), "seek to {}", target);
+        }
+        
+        // Forward from one target to the next, mixed with next
+        let mut r = PostReader::new(&ix, abc, None);
+        for target in (0..1010).step_by(97) {
+            let want = expected(&ids, target);
+            assert_eq!(seek(&mut r, target), want, "seek on to {}", target);
+            if let Some(id) = want {
+                let after = ids.iter().copied().find(|&i| i > id);
+                assert_eq!(r.next().then_some(r.fileid as u32), after, "next after {}", id);
+            }
+        }
+        
+        // Seeking back stays on the current id
+        let mut r = PostReader::new(&ix, abc, None);
+        assert_eq!(seek(&mut r, 700), Some(700));
+        assert_eq!(seek(&mut r, 10), Some(700));
+        
+        // Once past the end, seeking back finds nothing either
+        let mut r = PostReader::new(&ix, abc, None);
+        assert_eq!(seek(&mut r, 1005), None);
+        assert_eq!(seek(&mut r, 998), None);
+        assert_eq!(seek(&mut r, 0), None);
+        assert!(!r.next());
+        let mut r = PostReader::new(&ix, abc, None);
+        while r.next() {}
+        assert_eq!(seek(&mut r, *ids.last().unwrap()), None);
+        
+        // With a restriction, only its ids are found
+        let restrict: Vec<u32> = (0..1000).step_by(7).collect();
+        let allowed: Vec<u32> = ids.iter().copied().filter(|id| restrict.contains(id)).collect();
+        for target in (0..1010).step_by(13) {
+            let mut r = PostReader::new(&ix, abc, Some(restrict.clone()));
+            assert_eq!(seek(&mut r, target), expected(&allowed, target), "restricted seek to {}", target);
+        }
+        let mut r = PostReader::new(&ix, abc, Some(restrict.clone()));
+        assert_eq!(seek(&mut r, *allowed.last().unwrap() + 1), None);
+        assert_eq!(seek(&mut r, 0), None);
+    }
+}
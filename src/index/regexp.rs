@@ -466,15 +466,36 @@ fn simplify_set(s: &mut Vec<String>, is_suffix: bool) {
     *s = new_s;
 }
 
+#[allow(clippy::result_large_err)]
 pub fn analyze_regexp(pattern: &str) -> Result<Query, regex_syntax::Error> {
+    explain_regexp(pattern).map(|(q, _)| q)
+}
+
+/// Like `analyze_regexp`, but also describes the parts of the pattern
+/// that give no trigrams, which is why a query can end up matching all
+/// files.
+#[allow(clippy::result_large_err)]
+pub fn explain_regexp(pattern: &str) -> Result<(Query, Vec<String>), regex_syntax::Error> {
     let hir = regex_syntax::Parser::new().parse(pattern)?;
-    let mut info = analyze_hir(&hir);
+    let mut notes = Vec::new();
+    let mut info = analyze_hir(&hir, &mut notes);
     info.simplify(true);
     info.add_exact();
-    Ok(info.match_q)
+    Ok((info.match_q, notes))
 }
 
-fn analyze_hir(hir: &Hir) -> RegexpInfo {
+// Patterns quoted in notes are cut to this many characters
+const MAX_NOTE_PATTERN: usize = 40;
+
+fn note_pattern(hir: &Hir) -> String {
+    let s = hir.to_string().escape_debug().to_string();
+    match s.char_indices().nth(MAX_NOTE_PATTERN) {
+        Some((i, _)) => format!("{}...", &s[..i]),
+        None => s,
+    }
+}
+
+fn analyze_hir(hir: &Hir, notes: &mut Vec<String>) -> RegexpInfo {
     let mut info = match hir.kind() {
         HirKind::Empty => RegexpInfo::empty_string(),
         HirKind::Literal(lit) => {
@@ -487,7 +508,11 @@ fn analyze_hir(hir: &Hir) -> RegexpInfo {
                      info.match_q = Query::all();
                      info
                  }
-                 Err(_) => RegexpInfo::any_char(), // Fallback for invalid UTF-8
+                 Err(_) => {
+                     // Fallback for invalid UTF-8
+                     notes.push(format!("`{}` is not valid UTF-8 and is treated as any character", note_pattern(hir)));
+                     RegexpInfo::any_char()
+                 }
              }
         }
         HirKind::Class(cls) => {
@@ -505,6 +530,7 @@ fn analyze_hir(hir: &Hir) -> RegexpInfo {
                          // If too many, abort
                          let count = (end as u32) - (start as u32) + 1;
                          if chars.len() as u32 + count > 100 {
+                             notes.push(format!("`{}` is a class of more than 100 characters and is treated as any character", note_pattern(hir)));
                              return RegexpInfo::any_char();
                          }
                          for c in start..=end {
@@ -518,6 +544,7 @@ fn analyze_hir(hir: &Hir) -> RegexpInfo {
                          let end = range.end();
                          let count = (end as u16) - (start as u16) + 1;
                          if chars.len() as u16 + count > 100 {
+                             notes.push(format!("`{}` is a class of more than 100 bytes and is treated as any byte", note_pattern(hir)));
                              return RegexpInfo::any_char();
                          }
                          for c in start..=end {
@@ -539,10 +566,11 @@ fn analyze_hir(hir: &Hir) -> RegexpInfo {
         HirKind::Look(_) => RegexpInfo::empty_string(),
         HirKind::Repetition(rep) => {
              if rep.min == 0 {
+                 notes.push(format!("`{}` can repeat zero times and is treated as any text", note_pattern(hir)));
                  RegexpInfo::any_match()
              } else {
                  // Plus (min >= 1)
-                 let mut sub_info = analyze_hir(&rep.sub);
+                 let mut sub_info = analyze_hir(&rep.sub, notes);
                  if let Some(exact) = sub_info.exact {
                      sub_info.prefix = exact.clone();
                      sub_info.suffix = exact;
@@ -551,29 +579,30 @@ fn analyze_hir(hir: &Hir) -> RegexpInfo {
                  sub_info
              }
         }
-        HirKind::Capture(cap) => analyze_hir(&cap.sub),
+        HirKind::Capture(cap) => analyze_hir(&cap.sub, notes),
         HirKind::Concat(subs) => {
-            fold(concat_info, subs, RegexpInfo::empty_string())
+            fold(concat_info, subs, RegexpInfo::empty_string(), notes)
         }
         HirKind::Alternation(subs) => {
-            fold(alternate_info, subs, RegexpInfo::no_match())
+            fold(alternate_info, subs, RegexpInfo::no_match(), notes)
         }
     };
     info.simplify(false);
     info
 }
 
-fn fold<F>(f: F, subs: &[Hir], zero: RegexpInfo) -> RegexpInfo 
+fn fold<F>(f: F, subs: &[Hir], zero: RegexpInfo, notes: &mut Vec<String>) -> RegexpInfo 
 where F: Fn(RegexpInfo, RegexpInfo) -> RegexpInfo {
     if subs.is_empty() {
         return zero;
     }
     if subs.len() == 1 {
-        return analyze_hir(&subs[0]);
+        return analyze_hir(&subs[0], notes);
     }
-    let mut info = f(analyze_hir(&subs[0]), analyze_hir(&subs[1]));
-    for i in 2..subs.len() {
-        info = f(info, analyze_hir(&subs[i]));
+    let first = analyze_hir(&subs[0], notes);
+    let mut info = f(first, analyze_hir(&subs[1], notes));
+    for sub in &subs[2..] {
+        info = f(info, analyze_hir(sub, notes));
     }
     info
 }
@@ -0,0 +1,337 @@
+use std::cmp::Ordering;
+use std::fmt;
+use xxhash_rust::xxh64::xxh64;
+use crate::index::read::{DeltaReader, Index, PathReader, PostingTable};
+use crate::index::write::{cmp_paths, skip_count, FILE_META_SIZE, NUM_SECTIONS, SKIP_ENTRY_SIZE, SKIP_INTERVAL};
+
+const NAME_GROUP_SIZE: usize = 16;
+
+/// The sections of an index file, in file order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Header,
+    Roots,
+    Names,
+    Postings,
+    NameIndex,
+    PostIndex,
+    Metadata,
+    NamePostings,
+    NamePostIndex,
+}
+
+impl Section {
+    pub const ALL: [Section; NUM_SECTIONS] = [
+        Section::Header,
+        Section::Roots,
+        Section::Names,
+        Section::Postings,
+        Section::NameIndex,
+        Section::PostIndex,
+        Section::Metadata,
+        Section::NamePostings,
+        Section::NamePostIndex,
+    ];
+    
+    pub fn name(&self) -> &'static str {
+        match self {
+            Section::Header => "header",
+            Section::Roots => "roots",
+            Section::Names => "names",
+            Section::Postings => "posting lists",
+            Section::NameIndex => "name index",
+            Section::PostIndex => "posting index",
+            Section::Metadata => "file metadata",
+            Section::NamePostings => "name posting lists",
+            Section::NamePostIndex => "name posting index",
+        }
+    }
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A problem found by `Index::verify`.
+#[derive(Debug)]
+pub struct Corruption {
+    pub section: Section,
+    pub message: String,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.section, self.message)
+    }
+}
+
+impl Index {
+    /// Byte range of a section in the file, including its alignment
+    /// padding. Sections that the format doesn't have are empty.
+    pub fn section_range(&self, section: Section) -> (usize, usize) {
+        let post_index_end = self.meta_data.unwrap_or(self.trailer);
+        let meta_end = self.name_postings.map_or(self.trailer, |t| t.data);
+        let (name_post, name_post_index) = match self.name_postings {
+            Some(t) => ((t.data, t.index), (t.index, self.trailer)),
+            None => ((self.trailer, self.trailer), (self.trailer, self.trailer)),
+        };
+        match section {
+            Section::Header => (0, self.path_data),
+            Section::Roots => (self.path_data, self.name_data),
+            Section::Names => (self.name_data, self.post_data),
+            Section::Postings => (self.post_data, self.name_index),
+            Section::NameIndex => (self.name_index, self.post_index),
+            Section::PostIndex => (self.post_index, post_index_end),
+            Section::Metadata => (post_index_end, meta_end),
+            Section::NamePostings => name_post,
+            Section::NamePostIndex => name_post_index,
+        }
+    }
+    
+    /// Checks the whole index: the checksum of every section when the
+    /// format has them, and a full decode of every root, name and posting
+    /// list. Returns the problems found, at most one per section; an empty
+    /// list means the index is intact.
+    pub fn verify(&self) -> Vec<Corruption> {
+        let mut checks = Vec::new();
+        if let Some(sums) = &self.checksums {
+            for (&section, &sum) in Section::ALL.iter().zip(sums) {
+                checks.push((section, self.verify_checksum(section, sum)));
+            }
+        }
+        checks.push((Section::Roots, self.verify_roots()));
+        checks.push((Section::Names, self.verify_names()));
+        checks.push((Section::NameIndex, self.verify_name_index()));
+        let mut tables = vec![(self.content_postings(), Section::Postings, Section::PostIndex)];
+        if let Some(table) = self.name_postings {
+            tables.push((table, Section::NamePostings, Section::NamePostIndex));
+        }
+        for (table, lists, index) in tables {
+            let post_index = self.verify_post_index(&table, lists);
+            // Posting lists are found through the posting index
+            if post_index.is_ok() && !checks.iter().any(|(s, r)| *s == index && r.is_err()) {
+                checks.push((lists, self.verify_postings(&table, lists)));
+            }
+            checks.push((index, post_index));
+        }
+        checks.push((Section::Metadata, self.verify_meta()));
+        
+        // A corrupt checksum and a decoding error in the same section are
+        // one problem
+        let mut problems: Vec<Corruption> = Vec::new();
+        for (section, result) in checks {
+            if let Err(message) = result
+                && !problems.iter().any(|c| c.section == section) {
+                problems.push(Corruption { section, message });
+            }
+        }
+        problems
+    }
+    
+    fn verify_checksum(&self, section: Section, stored: u64) -> Result<(), String> {
+        let (start, end) = self.section_range(section);
+        let sum = xxh64(&self.mmap[start..end], 0);
+        if sum != stored {
+            return Err(format!("checksum mismatch: stored {:016x}, computed {:016x}", stored, sum));
+        }
+        Ok(())
+    }
+    
+    fn verify_roots(&self) -> Result<(), String> {
+        let (start, end) = self.section_range(Section::Roots);
+        let mut r = PathReader::new(&self.mmap[start..end], self.num_path);
+        verify_paths(&mut r, self.num_path, self.version >= 3)
+    }
+    
+    fn verify_names(&self) -> Result<(), String> {
+        let (start, end) = self.section_range(Section::Names);
+        let mut r = PathReader::new(&self.mmap[start..end], self.num_name);
+        verify_paths(&mut r, self.num_name, self.version >= 3)
+    }
+    
+    /// Each entry of the name index must point at the first name of its
+    /// group, which is stored without a shared prefix.
+    fn verify_name_index(&self) -> Result<(), String> {
+        let (start, end) = self.section_range(Section::NameIndex);
+        let (names_start, names_end) = self.section_range(Section::Names);
+        let num_group = self.num_name.div_ceil(NAME_GROUP_SIZE);
+        if num_group * 8 > end - start {
+            return Err(format!("{} entries do not fit in {} bytes", num_group, end - start));
+        }
+        let names = &self.mmap[names_start..names_end];
+        let mut r = PathReader::new(names, self.num_name);
+        for i in 0..self.num_name {
+            if i % NAME_GROUP_SIZE == 0 {
+                let g = i / NAME_GROUP_SIZE;
+                let off = be_u64(&self.mmap[start + g * 8..]) as usize;
+                let expected = names.len() - r.remaining();
+                if off != expected {
+                    return Err(format!("entry {} points to offset {}, expected {}", g, off, expected));
+                }
+            }
+            if r.next().is_none() {
+                // Reported with the names
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+    
+    /// The posting index lists every trigram once, in increasing order,
+    /// with offsets inside the section `lists` of its posting lists.
+    fn verify_post_index(&self, table: &PostingTable, lists: Section) -> Result<(), String> {
+        let (post_start, post_end) = self.section_range(lists);
+        let mut p = self.table_iter(*table);
+        let mut last = None;
+        let mut n = 0;
+        while let Some((t, count, offset)) = p.next() {
+            if last.is_some_and(|l| l >= t) {
+                return Err(format!("trigram {:06x} out of order at entry {}", t, n));
+            }
+            if count == 0 || count > self.num_name {
+                return Err(format!("trigram {:06x} has invalid count {}", t, count));
+            }
+            if offset.checked_add(post_start + 3).is_none_or(|e| e > post_end) {
+                return Err(format!("trigram {:06x} has offset {} outside the posting lists", t, offset));
+            }
+            last = Some(t);
+            n += 1;
+        }
+        if n != table.num_post {
+            return Err(format!("found {} of {} trigrams", n, table.num_post));
+        }
+        Ok(())
+    }
+    
+    /// Decodes every posting list: it must start with its trigram, hold
+    /// increasing file ids below the number of names, end with a zero delta
+    /// and be directly followed by the next list, or by its skip pointers,
+    /// which must point at the right ids.
+    fn verify_postings(&self, table: &PostingTable, lists: Section) -> Result<(), String> {
+        let (start, end) = self.section_range(lists);
+        let data = &self.mmap[start..end];
+        let mut p = self.table_iter(*table);
+        let mut expected = 0;
+        while let Some((t, count, offset)) = p.next() {
+            if offset.checked_add(3).is_none_or(|e| e > data.len()) {
+                // Reported with the posting index
+                return Ok(());
+            }
+            let num_skip = if self.version >= 6 { skip_count(count) } else { 0 };
+            let skips_start = expected;
+            expected += num_skip * SKIP_ENTRY_SIZE;
+            if offset != expected {
+                return Err(format!("list of trigram {:06x} at offset {}, expected {}", t, offset, expected));
+            }
+            let skips = &data[skips_start..offset];
+            let head = &data[offset..offset + 3];
+            if head != [(t >> 16) as u8, (t >> 8) as u8, t as u8] {
+                return Err(format!("list at offset {} does not start with trigram {:06x}", offset, t));
+            }
+            let list = &data[offset + 3..];
+            let mut delta = DeltaReader::new(list);
+            let mut fileid: i64 = -1;
+            for i in 0..count {
+                if i > 0 && i % SKIP_INTERVAL == 0 && i / SKIP_INTERVAL <= num_skip {
+                    let entry = &skips[(i / SKIP_INTERVAL - 1) * SKIP_ENTRY_SIZE..];
+                    let skip_id = be_u32(entry) as i64;
+                    let skip_bit = be_u32(&entry[4..]) as usize;
+                    if skip_id != fileid || skip_bit != delta.bit_position(list) {
+                        return Err(format!("skip pointer {} of trigram {:06x} does not match its list", i / SKIP_INTERVAL, t));
+                    }
+                }
+                match delta.next() {
+                    Some(d) if d > 0 => fileid += d as i64,
+                    _ => return Err(format!("list of trigram {:06x} is truncated or has an invalid delta", t)),
+                }
+            }
+            if fileid >= self.num_name as i64 {
+                return Err(format!("list of trigram {:06x} refers to file {} of {}", t, fileid, self.num_name));
+            }
+            if delta.next() != Some(0) {
+                return Err(format!("list of trigram {:06x} is not terminated", t));
+            }
+            expected = offset + 3 + list.len() - delta.remaining();
+        }
+        // Only alignment padding may follow the last list
+        if data[expected.min(data.len())..].iter().any(|&b| b != 0) {
+            return Err(format!("unexpected data after the last list at offset {}", expected));
+        }
+        Ok(())
+    }
+    
+    fn verify_meta(&self) -> Result<(), String> {
+        if self.meta_data.is_none() {
+            return Ok(());
+        }
+        let (start, end) = self.section_range(Section::Metadata);
+        let expected = self.num_name * FILE_META_SIZE;
+        if end - start != expected {
+            return Err(format!("{} bytes for {} files, expected {}", end - start, self.num_name, expected));
+        }
+        Ok(())
+    }
+}
+
+/// Decodes `count` paths, which must be unique and, when `ordered`, sorted
+/// in index path order.
+fn verify_paths(r: &mut PathReader, count: usize, ordered: bool) -> Result<(), String> {
+    let mut last: Option<String> = None;
+    for i in 0..count {
+        let Some(p) = r.next() else {
+            return Err(format!("cannot decode entry {} of {}", i, count));
+        };
+        if let Some(l) = &last
+            && ordered && cmp_paths(l, &p) != Ordering::Less {
+            return Err(format!("entry {} ({}) is out of order", i, p));
+        }
+        last = Some(p);
+    }
+    Ok(())
+}
+
+fn be_u32(b: &[u8]) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&b[..4]);
+    u32::from_be_bytes(buf)
+}
+
+fn be_u64(b: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&b[..8]);
+    u64::from_be_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::index::write::write_test_index;
+    
+    #[test]
+    fn verify_finds_a_flipped_byte_in_each_section() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("ix").to_string_lossy().into_owned();
+        let names: Vec<String> = (0..40).map(|i| format!("/r/d{}/file{:02}.rs", i % 4, i)).collect();
+        let mut names: Vec<&str> = names.iter().map(String::as_str).collect();
+        names.sort_by(|a, b| cmp_paths(a, b));
+        let files: Vec<(&str, &str)> = names.iter().map(|&name| (name, name)).collect();
+        write_test_index(&file, &["/r"], &files).unwrap();
+        let ix = Index::open(&file).unwrap();
+        assert!(ix.verify().is_empty());
+        let data = fs::read(&file).unwrap();
+        
+        for section in Section::ALL {
+            let (start, end) = ix.section_range(section);
+            assert!(start < end, "{} is empty", section);
+            let mut bad = data.clone();
+            bad[(start + end) / 2] ^= 0x10;
+            let bad_file = dir.path().join(format!("ix.{}", section)).to_string_lossy().into_owned();
+            fs::write(&bad_file, &bad).unwrap();
+            let problems = Index::open(&bad_file).unwrap().verify();
+            assert!(problems.iter().any(|c| c.section == section), "{}: {:?}", section, problems);
+        }
+    }
+}
@@ -1,9 +1,16 @@
 use std::fs::{self, File};
+use std::path::Path;
 use std::io::{self, BufWriter, Write, Seek, SeekFrom, Read};
 use std::cmp::{Ordering, min};
-use std::collections::BinaryHeap;
-use byteorder::{BigEndian, WriteBytesExt};
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+use std::time::UNIX_EPOCH;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
 use memmap2::Mmap;
+use tempfile::TempPath;
+use xxhash_rust::xxh64::{xxh64, Xxh64};
 use crate::sparse_set::Set as SparseSet;
 
 const NAME_GROUP_SIZE: usize = 16;
@@ -13,14 +20,47 @@ const MAX_TEXT_TRIGRAMS: usize = 20000;
 const INVALID_TRIGRAM: u32 = (1 << 24) - 1;
 const POST_BLOCK_SIZE: usize = 256;
 const DELTA_ZERO_ENC: u32 = 16;
-const WRITE_VERSION: i32 = 2;
+const SCAN_WINDOW_PER_THREAD: usize = 64;
+const WRITE_VERSION: i32 = 6;
+// Entries of the name posting lists built in memory at once
+const NAME_POST_BUDGET: usize = 1 << 23;
+
+pub(crate) const INDEX_MAGIC: &str = "csearch index 6\n";
+pub(crate) const TRAILER_MAGIC: &str = "\ncsearch trlr 6\n";
+/// Written on the line after the index magic to record what wrote the index.
+pub(crate) const TOOL_VERSION: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+/// Header, roots, names, posting lists, name index, posting index, file
+/// metadata, name posting lists and name posting index; each has a
+/// checksum in the trailer.
+pub const NUM_SECTIONS: usize = 9;
+/// Offsets and counts at the start of the trailer.
+pub const NUM_TRAILER_FIELDS: usize = 12;
+const CHECKPOINT_MAGIC: &str = "csearch checkpoint 1\n";
+/// File ids between two skip pointers of a posting list.
+pub(crate) const SKIP_INTERVAL: usize = 128;
+/// Shorter posting lists have no skip pointers.
+pub(crate) const SKIP_MIN_COUNT: usize = 2 * SKIP_INTERVAL;
+/// Size of a skip pointer: the file id before the checkpoint and the bit
+/// offset of the next delta, both big-endian u32.
+pub(crate) const SKIP_ENTRY_SIZE: usize = 8;
+
+/// Number of skip pointers stored before a posting list of `count` files
+/// (format 6 and later).
+pub(crate) fn skip_count(count: usize) -> usize {
+    if count < SKIP_MIN_COUNT { 0 } else { (count - 1) / SKIP_INTERVAL }
+}
 
 // --- Buffer ---
 
 pub struct IndexBuffer {
     file: File,
-    pub writer: BufWriter<File>,
+    writer: BufWriter<File>,
+    // Temporary file and destination of a buffer created by `for_publish`
+    publish: Option<(TempPath, String)>,
     pub offset: u64,
+    // Checksum of the bytes written since the current section started
+    hasher: Xxh64,
+    checksums: Vec<u64>,
 }
 
 impl IndexBuffer {
@@ -41,32 +81,96 @@ impl IndexBuffer {
         Ok(IndexBuffer {
             file,
             writer,
+            publish: None,
             offset: 0,
+            hasher: Xxh64::new(0),
+            checksums: Vec::new(),
         })
     }
-
+    
+    /// Creates a buffer for the file `dest` that is written to a temporary
+    /// file in the same directory. `dest` is only replaced, atomically, by
+    /// `publish`; until then readers keep seeing the old file, and dropping
+    /// the buffer removes the temporary file.
+    pub fn for_publish(dest: &str) -> io::Result<Self> {
+        let path = Path::new(dest);
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = format!(".{}.", path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default());
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(&prefix).suffix(".tmp");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            builder.permissions(fs::Permissions::from_mode(0o644));
+        }
+        let (file, temp) = builder.tempfile_in(dir)?.into_parts();
+        let writer = BufWriter::with_capacity(256 * 1024, file.try_clone()?);
+        
+        Ok(IndexBuffer {
+            file,
+            writer,
+            publish: Some((temp, dest.to_string())),
+            offset: 0,
+            hasher: Xxh64::new(0),
+            checksums: Vec::new(),
+        })
+    }
+    
+    /// Makes the content durable and renames it over the destination given
+    /// to `for_publish`.
+    pub fn publish(&mut self) -> io::Result<()> {
+        self.sync()?;
+        match self.publish.take() {
+            Some((temp, dest)) => temp.persist(dest).map_err(|e| e.error),
+            None => Err(io::Error::other("buffer has no destination to publish to")),
+        }
+    }
+    
+    /// Reopens a named buffer written by an earlier process, discarding
+    /// anything past `len` and appending from there.
+    pub fn reopen(name: &str, len: u64) -> io::Result<Self> {
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(name)?;
+        if file.metadata()?.len() < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: shorter than checkpoint", name)));
+        }
+        file.set_len(len)?;
+        file.seek(SeekFrom::Start(len))?;
+        let writer = BufWriter::with_capacity(256 * 1024, file.try_clone()?);
+        
+        Ok(IndexBuffer {
+            file,
+            writer,
+            publish: None,
+            offset: len,
+            hasher: Xxh64::new(0),
+            checksums: Vec::new(),
+        })
+    }
+    
     pub fn write_byte(&mut self, b: u8) -> io::Result<()> {
-        self.writer.write_all(&[b])?;
-        self.offset += 1;
-        Ok(())
+        self.write_bytes(&[b])
     }
-
+    
     pub fn write_bytes(&mut self, b: &[u8]) -> io::Result<()> {
         self.writer.write_all(b)?;
+        self.hasher.update(b);
         self.offset += b.len() as u64;
         Ok(())
     }
-
+    
     pub fn write_string(&mut self, s: &str) -> io::Result<()> {
         self.write_bytes(s.as_bytes())
     }
-
+    
     pub fn write_trigram(&mut self, t: u32) -> io::Result<()> {
         self.write_byte((t >> 16) as u8)?;
         self.write_byte((t >> 8) as u8)?;
         self.write_byte(t as u8)
     }
-
+    
     pub fn write_uvarint(&mut self, x: u64) -> io::Result<()> {
         let mut buf = [0u8; 10];
         let mut n = 0;
@@ -85,27 +189,76 @@ impl IndexBuffer {
         }
         self.write_bytes(&buf[..n])
     }
-
+    
     pub fn write_uint32(&mut self, x: u32) -> io::Result<()> {
-        self.writer.write_u32::<BigEndian>(x)?;
-        self.offset += 4;
-        Ok(())
+        self.write_bytes(&x.to_be_bytes())
     }
-
+    
     pub fn write_uint64(&mut self, x: u64) -> io::Result<()> {
-        self.writer.write_u64::<BigEndian>(x)?;
-        self.offset += 8;
-        Ok(())
+        self.write_bytes(&x.to_be_bytes())
     }
-
+    
+    /// Appends the whole content of `f`, typically a finished temporary buffer.
+    pub fn copy_from(&mut self, mut f: File) -> io::Result<()> {
+        let mut buf = vec![0u8; 256 * 1024];
+        loop {
+            let n = f.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            self.write_bytes(&buf[..n])?;
+        }
+    }
+    
     pub fn offset(&self) -> u64 {
         self.offset
     }
-
+    
+    /// Ends the current section, remembering its checksum for the trailer,
+    /// and returns the offset at which the next section starts.
+    pub fn start_section(&mut self) -> u64 {
+        self.checksums.push(self.hasher.digest());
+        self.hasher.reset(0);
+        self.offset
+    }
+    
+    /// Writes the index header: the magic and the version of this tool.
+    pub(crate) fn write_header(&mut self) -> io::Result<()> {
+        self.write_string(INDEX_MAGIC)?;
+        self.write_string(TOOL_VERSION)?;
+        self.write_byte(b'\n')
+    }
+    
+    /// Writes the trailer: the section offsets and counts in `fields`,
+    /// the checksum of each section, a checksum of the trailer itself,
+    /// and the trailer magic.
+    pub(crate) fn write_trailer(&mut self, fields: &[u64; NUM_TRAILER_FIELDS]) -> io::Result<()> {
+        self.start_section();
+        let checksums = std::mem::take(&mut self.checksums);
+        if checksums.len() != NUM_SECTIONS {
+            return Err(io::Error::other(format!("index written with {} sections", checksums.len())));
+        }
+        for &x in fields {
+            self.write_uint64(x)?;
+        }
+        for sum in checksums {
+            self.write_uint64(sum)?;
+        }
+        let sum = self.hasher.digest();
+        self.write_uint64(sum)?;
+        self.write_string(TRAILER_MAGIC)
+    }
+    
     pub fn flush(&mut self) -> io::Result<()> {
         self.writer.flush()
     }
-
+    
+    /// Flushes buffered data and waits until it reaches the disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.file.sync_data()
+    }
+    
     pub fn finish(mut self) -> io::Result<File> {
         self.flush()?;
         let mut f = self.file;
@@ -118,7 +271,7 @@ impl IndexBuffer {
             return Ok(());
         }
         let off = self.offset;
-        if off % n == 0 {
+        if off.is_multiple_of(n) {
             return Ok(());
         }
         let pad = n - (off % n);
@@ -131,17 +284,46 @@ impl IndexBuffer {
 
 // --- Delta Encoding ---
 
+/// Where a `DeltaWriter` puts its bytes.
+pub trait ByteSink {
+    fn put_byte(&mut self, b: u8) -> io::Result<()>;
+}
+
+impl ByteSink for IndexBuffer {
+    fn put_byte(&mut self, b: u8) -> io::Result<()> {
+        self.write_byte(b)
+    }
+}
+
+impl ByteSink for Vec<u8> {
+    fn put_byte(&mut self, b: u8) -> io::Result<()> {
+        self.push(b);
+        Ok(())
+    }
+}
+
 pub struct DeltaWriter {
     nb: u32, 
     b: u8,   
 }
 
+impl Default for DeltaWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DeltaWriter {
     pub fn new() -> Self {
         DeltaWriter { nb: 0, b: 0 }
     }
-
-    fn write_bits(&mut self, w_out: &mut IndexBuffer, mut x: u32, mut n: u32) -> io::Result<()> {
+    
+    /// Bits written into the byte not yet flushed.
+    pub fn pending_bits(&self) -> u32 {
+        self.nb
+    }
+    
+    fn write_bits<W: ByteSink>(&mut self, w_out: &mut W, mut x: u32, mut n: u32) -> io::Result<()> {
         while n > 0 {
             let space = 8 - self.nb;
             let mut w = n;
@@ -153,15 +335,15 @@ impl DeltaWriter {
             self.nb += w;
             n -= w;
             if self.nb == 8 {
-                w_out.write_byte(self.b)?;
+                w_out.put_byte(self.b)?;
                 self.b = 0;
                 self.nb = 0;
             }
         }
         Ok(())
     }
-
-    pub fn write(&mut self, w_out: &mut IndexBuffer, mut x: u32) -> io::Result<()> {
+    
+    pub fn write<W: ByteSink>(&mut self, w_out: &mut W, mut x: u32) -> io::Result<()> {
         if x == 0 {
             x = DELTA_ZERO_ENC;
         } else if x >= DELTA_ZERO_ENC {
@@ -175,9 +357,9 @@ impl DeltaWriter {
         self.write_bits(w_out, val, lg)
     }
     
-    pub fn finish(&mut self, w_out: &mut IndexBuffer) -> io::Result<()> {
+    pub fn finish<W: ByteSink>(&mut self, w_out: &mut W) -> io::Result<()> {
         if self.nb > 0 {
-            w_out.write_byte(self.b)?;
+            w_out.put_byte(self.b)?;
             self.nb = 0;
             self.b = 0;
         }
@@ -200,7 +382,7 @@ impl<'a> DeltaReader<'a> {
         self.b = 0;
         self.nb = 0;
     }
-
+    
     pub fn next(&mut self) -> Option<u32> {
         let i = self.next64()?;
         if i == DELTA_ZERO_ENC as u64 {
@@ -211,7 +393,7 @@ impl<'a> DeltaReader<'a> {
             Some(i as u32)
         }
     }
-
+    
     fn next64(&mut self) -> Option<u64> {
         let mut lg = 0;
         while self.b == 0 {
@@ -271,20 +453,90 @@ impl PartialOrd for IndexPath {
 
 impl Ord for IndexPath {
     fn cmp(&self, other: &Self) -> Ordering {
-        let a = self.s.as_bytes();
-        let b = other.s.as_bytes();
-        let len = min(a.len(), b.len());
-        for i in 0..len {
-            let mut ai = a[i];
-            let mut bi = b[i];
-            if ai == b'/' { ai = 0; }
-            if bi == b'/' { bi = 0; }
-            if ai != bi {
-                return ai.cmp(&bi);
-            }
+        cmp_paths(&self.s, &other.s)
+    }
+}
+
+/// Compares paths in index order: like byte order, except that '/' sorts
+/// before every other byte, so a directory's files directly follow its
+/// name and precede siblings such as `dir.c` or `dir-2`.
+pub fn cmp_paths(a: &str, b: &str) -> Ordering {
+    let a = a.as_bytes();
+    let b = b.as_bytes();
+    let len = min(a.len(), b.len());
+    for i in 0..len {
+        let mut ai = a[i];
+        let mut bi = b[i];
+        if ai == b'/' { ai = 0; }
+        if bi == b'/' { bi = 0; }
+        if ai != bi {
+            return ai.cmp(&bi);
         }
-        a.len().cmp(&b.len())
     }
+    a.len().cmp(&b.len())
+}
+
+/// Reports whether `name` is `dir` itself or lies below it. Unlike a plain
+/// prefix test this respects path components: `/src/foobar` is not within
+/// `/src/foo`.
+pub fn path_within(name: &str, dir: &str) -> bool {
+    match name.strip_prefix(dir) {
+        Some(rest) => rest.is_empty() || rest.starts_with(std::path::is_separator) || dir.ends_with(std::path::is_separator),
+        None => false,
+    }
+}
+
+// --- File Metadata ---
+
+/// Size in bytes of one entry in the file metadata section.
+pub const FILE_META_SIZE: usize = 24;
+
+/// Per-file metadata stored next to each name, used by `cindex` to tell
+/// whether a file changed since it was last indexed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileMeta {
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch.
+    pub mtime: u64,
+    /// xxh64 of the file contents.
+    pub hash: u64,
+}
+
+impl FileMeta {
+    pub fn new(md: &fs::Metadata, hash: u64) -> Self {
+        FileMeta { size: md.len(), mtime: mtime_nanos(md), hash }
+    }
+    
+    /// Decode an entry of the metadata section.
+    pub fn decode(b: &[u8]) -> Self {
+        FileMeta {
+            size: BigEndian::read_u64(&b[0..8]),
+            mtime: BigEndian::read_u64(&b[8..16]),
+            hash: BigEndian::read_u64(&b[16..24]),
+        }
+    }
+    
+    pub fn write(&self, out: &mut IndexBuffer) -> io::Result<()> {
+        out.write_uint64(self.size)?;
+        out.write_uint64(self.mtime)?;
+        out.write_uint64(self.hash)
+    }
+    
+    /// Reports whether size and mtime still match `md`, in which case the
+    /// file is assumed unchanged without reading it.
+    pub fn same_stat(&self, md: &fs::Metadata) -> bool {
+        self.size == md.len() && self.mtime == mtime_nanos(md)
+    }
+}
+
+pub fn mtime_nanos(md: &fs::Metadata) -> u64 {
+    md.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+pub fn content_hash(data: &[u8]) -> u64 {
+    xxh64(data, 0)
 }
 
 pub struct PathWriterState {
@@ -321,16 +573,14 @@ impl<'a> PathWriter<'a> {
             start,
         }
     }
-
+    
     pub fn write(&mut self, p: &IndexPath) -> io::Result<()> {
-        let write_index = (self.state.group == 0 && self.state.n == 0) || (self.state.group > 0 && self.state.n % self.state.group == 0);
+        let write_index = (self.state.group == 0 && self.state.n == 0) || (self.state.group > 0 && self.state.n.is_multiple_of(self.state.group));
         
-        if write_index {
-             if let Some(ref mut idx) = self.index {
-                 // Write 8-byte offset for compatibility with reader
-                 let off = self.data.offset() - self.start;
-                 idx.write_uint64(off)?;
-             }
+        if write_index && let Some(ref mut idx) = self.index {
+             // Write 8-byte offset for compatibility with reader
+             let off = self.data.offset() - self.start;
+             idx.write_uint64(off)?;
         }
         
         let mut pre = 0;
@@ -358,6 +608,11 @@ impl<'a> PathWriter<'a> {
 
 // --- Post Data ---
 
+/// Writes posting lists, and their posting index when given one. Indexed
+/// lists are the final ones: a list of at least `SKIP_MIN_COUNT` files is
+/// preceded by skip pointers, one for every `SKIP_INTERVAL` files, so that
+/// readers can jump ahead without decoding the whole list. The ids of such
+/// a list are kept until `end_trigram`, when the number of pointers is known.
 pub struct PostDataWriter<'a> {
     out: &'a mut IndexBuffer,
     post_index: Option<&'a mut IndexBuffer>,
@@ -370,6 +625,8 @@ pub struct PostDataWriter<'a> {
     pub num_trigram: usize,
     count: usize, // number of files for current trigram
     block: Vec<u8>,
+    ids: Vec<i32>,
+    list: Vec<u8>,
 }
 
 impl<'a> PostDataWriter<'a> {
@@ -387,30 +644,69 @@ impl<'a> PostDataWriter<'a> {
             num_trigram: 0,
             count: 0,
             block: Vec::with_capacity(POST_BLOCK_SIZE),
+            ids: Vec::new(),
+            list: Vec::new(),
         }
     }
     
     pub fn trigram(&mut self, t: u32) -> io::Result<()> {
         if t == 0 { panic!("invalid trigram"); }
-        self.offset = self.out.offset();
         self.t = t;
         self.last_id = -1;
         self.count = 0;
         self.num_trigram += 1;
+        if self.post_index.is_some() {
+            self.ids.clear();
+            return Ok(());
+        }
+        self.offset = self.out.offset();
         self.out.write_trigram(t)
     }
     
     pub fn fileid(&mut self, id: i32) -> io::Result<()> {
-        let diff = id - self.last_id;
-        self.delta.write(self.out, diff as u32)?;
+        if self.post_index.is_some() {
+            self.ids.push(id);
+        } else {
+            let diff = id - self.last_id;
+            self.delta.write(self.out, diff as u32)?;
+        }
         self.last_id = id;
         self.count += 1;
         Ok(())
     }
     
+    /// Writes the list buffered for an indexed trigram, after its skip
+    /// pointers.
+    fn write_list(&mut self) -> io::Result<()> {
+        let num_skip = skip_count(self.ids.len());
+        let mut skips = Vec::with_capacity(num_skip * SKIP_ENTRY_SIZE);
+        self.list.clear();
+        let mut last = -1;
+        for (i, &id) in self.ids.iter().enumerate() {
+            if i > 0 && i % SKIP_INTERVAL == 0 && i / SKIP_INTERVAL <= num_skip {
+                let bit = self.list.len() * 8 + self.delta.pending_bits() as usize;
+                skips.extend_from_slice(&(last as u32).to_be_bytes());
+                skips.extend_from_slice(&(bit as u32).to_be_bytes());
+            }
+            self.delta.write(&mut self.list, (id - last) as u32)?;
+            last = id;
+        }
+        self.delta.write(&mut self.list, 0)?;
+        self.delta.finish(&mut self.list)?;
+        
+        self.out.write_bytes(&skips)?;
+        self.offset = self.out.offset();
+        self.out.write_trigram(self.t)?;
+        self.out.write_bytes(&self.list)
+    }
+    
     pub fn end_trigram(&mut self) -> io::Result<()> {
-        self.delta.write(self.out, 0)?;
-        self.delta.finish(self.out)?;
+        if self.post_index.is_some() {
+            self.write_list()?;
+        } else {
+            self.delta.write(self.out, 0)?;
+            self.delta.finish(self.out)?;
+        }
         
         if let Some(ref mut idx) = self.post_index {
              let mut buf = [0u8; 3 + 10 + 10 + 10];
@@ -453,17 +749,88 @@ impl<'a> PostDataWriter<'a> {
     }
     
     pub fn flush(&mut self) -> io::Result<()> {
-        if let Some(ref mut idx) = self.post_index {
-            if !self.block.is_empty() {
-                self.block.resize(POST_BLOCK_SIZE, 0);
-                idx.write_bytes(&self.block)?;
-                self.block.clear();
-            }
+        if let Some(ref mut idx) = self.post_index
+            && !self.block.is_empty() {
+            self.block.resize(POST_BLOCK_SIZE, 0);
+            idx.write_bytes(&self.block)?;
+            self.block.clear();
         }
         Ok(())
     }
 }
 
+// --- Name Postings ---
+
+/// Writes posting lists over the trigrams of the file names into `out`,
+/// and their posting index into `post_index`, so that names can be searched
+/// without decoding all of them. `names` is a names section holding
+/// `num_name` names. Returns the number of trigrams.
+///
+/// The lists are built in passes over ranges of trigrams, each holding at
+/// most about `NAME_POST_BUDGET` entries in memory.
+pub(crate) fn write_name_postings(names: &[u8], num_name: usize, out: &mut IndexBuffer, post_index: &mut IndexBuffer) -> io::Result<usize> {
+    let name_trigrams = |name: &str, trigrams: &mut Vec<u32>| {
+        trigrams.clear();
+        trigrams.extend(name.as_bytes().windows(3).map(|b| (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32));
+        trigrams.retain(|&t| t != 0);
+        trigrams.sort_unstable();
+        trigrams.dedup();
+    };
+    
+    // Count the entries by the first byte of their trigram to plan the passes
+    let mut counts = [0usize; 256];
+    let mut trigrams = Vec::new();
+    let mut r = crate::index::read::PathReader::new(names, num_name);
+    while let Some(name) = r.next() {
+        name_trigrams(&name, &mut trigrams);
+        for &t in &trigrams {
+            counts[(t >> 16) as usize] += 1;
+        }
+    }
+    let mut passes = Vec::new();
+    let (mut lo, mut n) = (0, 0);
+    for (b, &count) in counts.iter().enumerate() {
+        if n > 0 && n + count > NAME_POST_BUDGET {
+            passes.push((lo, b as u32));
+            lo = b as u32;
+            n = 0;
+        }
+        n += count;
+    }
+    passes.push((lo, 256));
+    
+    let mut w = PostDataWriter::new(out, Some(post_index));
+    let mut post = Vec::new();
+    for (lo, hi) in passes {
+        post.clear();
+        let mut r = crate::index::read::PathReader::new(names, num_name);
+        let mut id = 0;
+        while let Some(name) = r.next() {
+            name_trigrams(&name, &mut trigrams);
+            for &t in &trigrams {
+                if (lo..hi).contains(&(t >> 16)) {
+                    post.push(PostEntry::new(t, id));
+                }
+            }
+            id += 1;
+        }
+        post.sort_unstable();
+        
+        let mut i = 0;
+        while i < post.len() {
+            let t = post[i].trigram();
+            w.trigram(t)?;
+            while i < post.len() && post[i].trigram() == t {
+                w.fileid(post[i].fileid())?;
+                i += 1;
+            }
+            w.end_trigram()?;
+        }
+    }
+    w.flush()?;
+    Ok(w.num_trigram)
+}
+
 // --- Merging ---
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -563,10 +930,155 @@ impl Ord for HeapItem {
 }
 
 
+// --- Scanning ---
+
+/// A file that was read and scanned, ready to be added to an index.
+pub struct ScannedFile {
+    pub name: String,
+    pub meta: FileMeta,
+    /// Distinct trigrams in first-seen order.
+    pub trigrams: Vec<u32>,
+    /// The file is not indexed: it is binary, too long or has too many
+    /// trigrams. Only its size and mtime are known.
+    pub skipped: bool,
+}
+
+impl ScannedFile {
+    fn skipped(name: &str, md: &fs::Metadata) -> Self {
+        ScannedFile { name: name.to_string(), meta: FileMeta::new(md, 0), trigrams: Vec::new(), skipped: true }
+    }
+}
+
+/// Reads files and extracts their trigram sets. Scanning does not touch
+/// the index, so each indexing thread can own a scanner.
+pub struct FileScanner {
+    trigram: SparseSet,
+    pub log_skip: bool,
+}
+
+impl Default for FileScanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileScanner {
+    pub fn new() -> Self {
+        FileScanner {
+            trigram: SparseSet::new(1 << 24),
+            log_skip: false,
+        }
+    }
+    
+    /// Reads and scans `name`. Returns `None` for files that cannot be
+    /// read, and a file marked `skipped` for those that should not be
+    /// indexed: too long, binary or with too many trigrams.
+    pub fn scan(&mut self, name: &str) -> io::Result<Option<ScannedFile>> {
+        let f = File::open(name);
+        if f.is_err() {
+            if self.log_skip { eprintln!("{}: {}", name, f.err().unwrap()); }
+            return Ok(None);
+        }
+        let mut f = f.unwrap();
+        let md = f.metadata()?;
+        let len = md.len();
+        if len > MAX_FILE_LEN {
+             if self.log_skip { eprintln!("{}: too long, ignoring", name); }
+             return Ok(Some(ScannedFile::skipped(name, &md)));
+        }
+        
+        let mut buf = Vec::with_capacity(len as usize + 1);
+        f.read_to_end(&mut buf)?;
+        
+        self.trigram.reset();
+        let mut tv: u32 = 0;
+        let mut n = 0;
+        let mut linelen = 0;
+        
+        for &c in &buf {
+            tv = (tv << 8) & 0xFFFFFF;
+            tv |= c as u32;
+            n += 1;
+            if n >= 3 {
+                self.trigram.add(tv);
+            }
+            if c == 0 {
+                if self.log_skip { eprintln!("{}: contains NUL, ignoring", name); }
+                return Ok(Some(ScannedFile::skipped(name, &md)));
+            }
+            // Note: We don't validate UTF-8 here as many source files use Latin-1 or other encodings.
+            // The NUL check above is sufficient to skip binary files.
+            if linelen > MAX_LINE_LEN {
+                 if self.log_skip { eprintln!("{}: very long lines, ignoring", name); }
+                 return Ok(Some(ScannedFile::skipped(name, &md)));
+            }
+            linelen += 1;
+            if c == b'\n' { linelen = 0; }
+        }
+        
+        if self.trigram.len() > MAX_TEXT_TRIGRAMS {
+            if self.log_skip { eprintln!("{}: too many trigrams, ignoring", name); }
+            return Ok(Some(ScannedFile::skipped(name, &md)));
+        }
+        
+        Ok(Some(ScannedFile {
+            name: name.to_string(),
+            meta: FileMeta::new(&md, content_hash(&buf)),
+            trigrams: self.trigram.dense().to_vec(),
+            skipped: false,
+        }))
+    }
+}
+
+// --- Checkpoints ---
+
+/// Paths of a checkpoint sidecar: the state file and the files the writer
+/// appends names, metadata and flushed posting runs to.
+struct CheckpointFiles {
+    state: String,
+    names: String,
+    name_index: String,
+    post: String,
+    meta: String,
+}
+
+impl CheckpointFiles {
+    fn new(sidecar: &str) -> Self {
+        CheckpointFiles {
+            state: sidecar.to_string(),
+            names: format!("{}.names", sidecar),
+            name_index: format!("{}.nameidx", sidecar),
+            post: format!("{}.post", sidecar),
+            meta: format!("{}.meta", sidecar),
+        }
+    }
+    
+    fn remove(&self) {
+        for f in [&self.state, &self.names, &self.name_index, &self.post, &self.meta] {
+            let _ = fs::remove_file(f);
+        }
+    }
+}
+
+fn read_checkpoint_string(r: &mut &[u8]) -> io::Result<String> {
+    let n = r.read_u64::<BigEndian>()? as usize;
+    if n > r.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated checkpoint"));
+    }
+    let (s, rest) = r.split_at(n);
+    *r = rest;
+    String::from_utf8(s.to_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_checkpoint_string(out: &mut IndexBuffer, s: &str) -> io::Result<()> {
+    out.write_uint64(s.len() as u64)?;
+    out.write_string(s)
+}
+
 // --- IndexWriter ---
 
 pub struct IndexWriter {
-    trigram: SparseSet,
+    scanner: FileScanner,
     post: Vec<PostEntry>,
     
     // Buffers as Options to take ownership in flush
@@ -574,6 +1086,7 @@ pub struct IndexWriter {
     post_buf: Option<IndexBuffer>,
     name_index_buf: Option<IndexBuffer>,
     post_index_buf: Option<IndexBuffer>,
+    meta_buf: Option<IndexBuffer>,
     
     main_buf: IndexBuffer, 
     
@@ -585,11 +1098,17 @@ pub struct IndexWriter {
     
     pub verbose: bool,
     pub log_skip: bool,
-
+    
     roots: Vec<String>,
+    /// Files passed to the writer that were not indexed, with their
+    /// metadata.
+    skipped: Vec<(String, FileMeta)>,
     
     // State
     name_writer_state: PathWriterState,
+    
+    file: String,
+    checkpoint: Option<CheckpointFiles>,
 }
 
 impl IndexWriter {
@@ -597,87 +1116,320 @@ impl IndexWriter {
         let name_buf = IndexBuffer::new("")?;
         let post_buf = IndexBuffer::new("")?;
         let name_index_buf = IndexBuffer::new("")?;
+        let meta_buf = IndexBuffer::new("")?;
+        Self::with_buffers(file, name_buf, post_buf, name_index_buf, meta_buf, None)
+    }
+    
+    /// Creates a writer that can save its progress with `checkpoint` into
+    /// the sidecar `sidecar` and be restored later with `resume`.
+    pub fn create_checkpointed(file: &str, sidecar: &str) -> io::Result<Self> {
+        let files = CheckpointFiles::new(sidecar);
+        // A stale state file must not describe the buffers truncated below
+        let _ = fs::remove_file(&files.state);
+        let name_buf = IndexBuffer::new(&files.names)?;
+        let post_buf = IndexBuffer::new(&files.post)?;
+        let name_index_buf = IndexBuffer::new(&files.name_index)?;
+        let meta_buf = IndexBuffer::new(&files.meta)?;
+        Self::with_buffers(file, name_buf, post_buf, name_index_buf, meta_buf, Some(files))
+    }
+    
+    fn with_buffers(file: &str, name_buf: IndexBuffer, post_buf: IndexBuffer, name_index_buf: IndexBuffer, meta_buf: IndexBuffer, checkpoint: Option<CheckpointFiles>) -> io::Result<Self> {
         let post_index_buf = IndexBuffer::new("")?;
-        let main_buf = IndexBuffer::new(file)?;
+        let main_buf = IndexBuffer::for_publish(file)?;
         
         Ok(IndexWriter {
-            trigram: SparseSet::new(1 << 24),
+            scanner: FileScanner::new(),
             post: Vec::with_capacity(256 * 1024), 
             name_buf: Some(name_buf),
             post_buf: Some(post_buf),
             name_index_buf: Some(name_index_buf),
             post_index_buf: Some(post_index_buf),
+            meta_buf: Some(meta_buf),
             main_buf,
             num_name: 0,
             num_trigram: 0,
             total_bytes: 0,
             post_ends: Vec::new(),
             roots: Vec::new(),
+            skipped: Vec::new(),
             verbose: false,
             log_skip: false,
             name_writer_state: PathWriterState::new(NAME_GROUP_SIZE),
+            file: file.to_string(),
+            checkpoint,
         })
     }
     
+    /// Restores a writer from the last checkpoint saved in `sidecar`. Adding
+    /// the remaining files and flushing yields the same index as a run that
+    /// was never interrupted.
+    pub fn resume(sidecar: &str) -> io::Result<Self> {
+        let files = CheckpointFiles::new(sidecar);
+        let data = fs::read(&files.state)?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{}: not a checkpoint", sidecar));
+        let mut r = data.strip_prefix(CHECKPOINT_MAGIC.as_bytes()).ok_or_else(invalid)?;
+        
+        let file = read_checkpoint_string(&mut r)?;
+        let num_name = r.read_u64::<BigEndian>()? as usize;
+        let total_bytes = r.read_u64::<BigEndian>()? as i64;
+        let names_len = r.read_u64::<BigEndian>()?;
+        let name_index_len = r.read_u64::<BigEndian>()?;
+        let post_len = r.read_u64::<BigEndian>()?;
+        let meta_len = r.read_u64::<BigEndian>()?;
+        let mut post_ends = Vec::new();
+        for _ in 0..r.read_u64::<BigEndian>()? {
+            post_ends.push(r.read_u64::<BigEndian>()?);
+        }
+        let mut roots = Vec::new();
+        for _ in 0..r.read_u64::<BigEndian>()? {
+            roots.push(read_checkpoint_string(&mut r)?);
+        }
+        let last = read_checkpoint_string(&mut r)?;
+        let n = r.read_u64::<BigEndian>()? as usize;
+        
+        let name_buf = IndexBuffer::reopen(&files.names, names_len)?;
+        let post_buf = IndexBuffer::reopen(&files.post, post_len)?;
+        let name_index_buf = IndexBuffer::reopen(&files.name_index, name_index_len)?;
+        let meta_buf = IndexBuffer::reopen(&files.meta, meta_len)?;
+        
+        let mut ix = Self::with_buffers(&file, name_buf, post_buf, name_index_buf, meta_buf, Some(files))?;
+        ix.num_name = num_name;
+        ix.total_bytes = total_bytes;
+        ix.post_ends = post_ends;
+        ix.roots = roots;
+        ix.name_writer_state = PathWriterState { last, n, group: NAME_GROUP_SIZE, start: Some(0) };
+        Ok(ix)
+    }
+    
+    /// Removes all files of the checkpoint sidecar `sidecar`.
+    pub fn remove_checkpoint(sidecar: &str) {
+        CheckpointFiles::new(sidecar).remove();
+    }
+    
+    /// Number of files written to the index so far.
+    pub fn num_files(&self) -> usize {
+        self.num_name
+    }
+    
+    /// The files this writer was given but did not index because they are
+    /// binary, too long or have too many trigrams, in the order given.
+    /// Files skipped before a checkpoint are not restored by `resume`.
+    pub fn skipped_files(&self) -> &[(String, FileMeta)] {
+        &self.skipped
+    }
+    
+    /// The last name added, or an empty string.
+    pub fn last_name(&self) -> &str {
+        &self.name_writer_state.last
+    }
+    
+    /// Discards every file added so far, including those restored from a
+    /// checkpoint, keeping the roots. Only available on checkpointed writers.
+    pub fn restart(&mut self) -> io::Result<()> {
+        let Some(files) = &self.checkpoint else {
+            return Err(io::Error::other("writer has no checkpoint"));
+        };
+        let mut ix = Self::create_checkpointed(&self.file, &files.state)?;
+        ix.verbose = self.verbose;
+        ix.log_skip = self.log_skip;
+        ix.roots = std::mem::take(&mut self.roots);
+        *self = ix;
+        Ok(())
+    }
+    
+    /// The index file this writer produces.
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+    
+    /// Names of the files added so far, including those restored from a
+    /// checkpoint. Only available on checkpointed writers.
+    pub fn indexed_names(&mut self) -> io::Result<Vec<String>> {
+        let Some(files) = &self.checkpoint else {
+            return Err(io::Error::other("writer has no checkpoint"));
+        };
+        self.name_buf.as_mut().unwrap().flush()?;
+        let data = fs::read(&files.names)?;
+        let mut r = crate::index::read::PathReader::new(&data, self.num_name);
+        let mut names = Vec::with_capacity(self.num_name);
+        while let Some(name) = r.next() {
+            names.push(name);
+        }
+        Ok(names)
+    }
+    
+    /// Makes everything added so far durable in the checkpoint sidecar. The
+    /// in-memory postings are flushed as a run, the buffer files are synced
+    /// and then the state describing them is atomically replaced.
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        if self.checkpoint.is_none() {
+            return Err(io::Error::other("writer has no checkpoint"));
+        }
+        if !self.post.is_empty() {
+            self.flush_post()?;
+        }
+        let name_buf = self.name_buf.as_mut().unwrap();
+        let post_buf = self.post_buf.as_mut().unwrap();
+        let name_index_buf = self.name_index_buf.as_mut().unwrap();
+        let meta_buf = self.meta_buf.as_mut().unwrap();
+        for b in [&mut *name_buf, &mut *post_buf, &mut *name_index_buf, &mut *meta_buf] {
+            b.sync()?;
+        }
+        
+        let files = self.checkpoint.as_ref().unwrap();
+        let tmp = format!("{}.tmp", files.state);
+        let mut out = IndexBuffer::new(&tmp)?;
+        out.write_string(CHECKPOINT_MAGIC)?;
+        write_checkpoint_string(&mut out, &self.file)?;
+        out.write_uint64(self.num_name as u64)?;
+        out.write_uint64(self.total_bytes as u64)?;
+        out.write_uint64(name_buf.offset())?;
+        out.write_uint64(name_index_buf.offset())?;
+        out.write_uint64(post_buf.offset())?;
+        out.write_uint64(meta_buf.offset())?;
+        out.write_uint64(self.post_ends.len() as u64)?;
+        for &end in &self.post_ends {
+            out.write_uint64(end)?;
+        }
+        out.write_uint64(self.roots.len() as u64)?;
+        for r in &self.roots {
+            write_checkpoint_string(&mut out, r)?;
+        }
+        write_checkpoint_string(&mut out, &self.name_writer_state.last)?;
+        out.write_uint64(self.name_writer_state.n as u64)?;
+        out.sync()?;
+        fs::rename(&tmp, &files.state)
+    }
+    
     pub fn add_root(&mut self, root: &str) {
-        self.roots.push(root.to_string());
+        // A resumed writer already holds the roots of the interrupted run
+        if !self.roots.iter().any(|r| r == root) {
+            self.roots.push(root.to_string());
+        }
     }
-
+    
     pub fn add_file(&mut self, name: &str) -> io::Result<()> {
-        let f = File::open(name);
-        if f.is_err() {
-            if self.log_skip { eprintln!("{}: {}", name, f.err().unwrap()); }
-            return Ok(());
+        self.scanner.log_skip = self.log_skip;
+        if let Some(f) = self.scanner.scan(name)? {
+            self.add_scanned(f)?;
         }
-        let mut f = f.unwrap();
-        let len = f.metadata()?.len();
-        if len > MAX_FILE_LEN {
-             if self.log_skip { eprintln!("{}: too long, ignoring", name); }
-             return Ok(());
+        Ok(())
+    }
+    
+    /// Adds `files` in order, reading and scanning them on `threads` worker
+    /// threads. File ids are still assigned by this thread in the order of
+    /// `files`, so the index is identical to calling `add_file` on each name
+    /// in turn. `added` runs on the calling thread after each file.
+    pub fn add_files<E, F>(&mut self, files: &[String], threads: usize, mut added: F) -> Result<(), E>
+    where
+        E: From<io::Error>,
+        F: FnMut(&mut IndexWriter, &str) -> Result<(), E>,
+    {
+        if threads <= 1 || files.len() <= 1 {
+            for name in files {
+                self.add_file(name)?;
+                added(self, name)?;
+            }
+            return Ok(());
         }
         
-        let mut buf = Vec::with_capacity(len as usize + 1);
-        f.read_to_end(&mut buf)?;
+        // Workers may run at most `window` files ahead of the writer, which
+        // bounds the number of scanned files held in memory.
+        let window = threads * SCAN_WINDOW_PER_THREAD;
+        let next = AtomicUsize::new(0);
+        let consumed = Mutex::new(0usize);
+        let progress = Condvar::new();
+        let stop = AtomicBool::new(false);
+        let log_skip = self.log_skip;
         
-        self.trigram.reset();
-        let mut tv: u32 = 0;
-        let mut n = 0;
-        let mut linelen = 0;
-        
-        for &c in &buf {
-            tv = (tv << 8) & 0xFFFFFF;
-            tv |= c as u32;
-            n += 1;
-            if n >= 3 {
-                self.trigram.add(tv);
-            }
-            if c == 0 {
-                if self.log_skip { eprintln!("{}: contains NUL, ignoring", name); }
-                return Ok(());
+        thread::scope(|scope| {
+            let (tx, rx) = mpsc::channel();
+            for _ in 0..threads {
+                let tx = tx.clone();
+                let (next, consumed, progress, stop) = (&next, &consumed, &progress, &stop);
+                scope.spawn(move || {
+                    let mut scanner = FileScanner::new();
+                    scanner.log_skip = log_skip;
+                    loop {
+                        let i = next.fetch_add(1, AtomicOrdering::Relaxed);
+                        if i >= files.len() {
+                            break;
+                        }
+                        let mut n = consumed.lock().unwrap();
+                        while i >= *n + window && !stop.load(AtomicOrdering::Relaxed) {
+                            n = progress.wait(n).unwrap();
+                        }
+                        drop(n);
+                        if stop.load(AtomicOrdering::Relaxed) {
+                            break;
+                        }
+                        if tx.send((i, scanner.scan(&files[i]))).is_err() {
+                            break;
+                        }
+                    }
+                });
             }
-            // Note: We don't validate UTF-8 here as many source files use Latin-1 or other encodings.
-            // The NUL check above is sufficient to skip binary files.
-            if linelen > MAX_LINE_LEN {
-                 if self.log_skip { eprintln!("{}: very long lines, ignoring", name); }
-                 return Ok(());
+            drop(tx);
+            
+            let result = self.add_scanned_in_order(files, &rx, &consumed, &progress, &mut added);
+            // Under the lock, so that a worker cannot check `stop` and then
+            // miss the notification
+            let n = consumed.lock().unwrap();
+            stop.store(true, AtomicOrdering::Relaxed);
+            progress.notify_all();
+            drop(n);
+            result
+        })
+    }
+    
+    fn add_scanned_in_order<E, F>(
+        &mut self,
+        files: &[String],
+        rx: &mpsc::Receiver<(usize, io::Result<Option<ScannedFile>>)>,
+        consumed: &Mutex<usize>,
+        progress: &Condvar,
+        added: &mut F,
+    ) -> Result<(), E>
+    where
+        E: From<io::Error>,
+        F: FnMut(&mut IndexWriter, &str) -> Result<(), E>,
+    {
+        let mut pending = HashMap::new();
+        for (i, name) in files.iter().enumerate() {
+            let scanned = loop {
+                if let Some(r) = pending.remove(&i) {
+                    break r;
+                }
+                match rx.recv() {
+                    Ok((j, r)) => { pending.insert(j, r); }
+                    Err(_) => return Err(io::Error::other("scanner threads exited early").into()),
+                }
+            };
+            if let Some(f) = scanned? {
+                self.add_scanned(f)?;
             }
-            linelen += 1;
-            if c == b'\n' { linelen = 0; }
+            added(self, name)?;
+            *consumed.lock().unwrap() = i + 1;
+            progress.notify_all();
         }
-        
-        if self.trigram.len() > MAX_TEXT_TRIGRAMS {
-            if self.log_skip { eprintln!("{}: too many trigrams, ignoring", name); }
+        Ok(())
+    }
+    
+    /// Adds a file that was already read and scanned by a `FileScanner`.
+    /// A skipped file is only recorded in `skipped_files`.
+    pub fn add_scanned(&mut self, f: ScannedFile) -> io::Result<()> {
+        if f.skipped {
+            self.skipped.push((f.name, f.meta));
             return Ok(());
         }
+        self.total_bytes += f.meta.size as i64;
+        let fileid = self.add_name(&f.name)?;
+        f.meta.write(self.meta_buf.as_mut().unwrap())?;
         
-        self.total_bytes += len as i64;
-        let fileid = self.add_name(name)?;
-        
-        let trigrams = self.trigram.dense().to_vec();
         if self.verbose {
-            println!("DEBUG: File {} added {} trigrams", name, trigrams.len());
+            println!("DEBUG: File {} added {} trigrams", f.name, f.trigrams.len());
         }
-        for trigram in trigrams {
+        for trigram in f.trigrams {
             if self.post.len() >= self.post.capacity() {
                 self.flush_post()?;
             }
@@ -692,7 +1444,7 @@ impl IndexWriter {
         
         let mut writer = PathWriter::new(
             self.name_buf.as_mut().unwrap(),
-            self.name_index_buf.as_mut().map(|b| b),
+            self.name_index_buf.as_mut(),
             &mut self.name_writer_state
         );
         writer.write(&IndexPath::new(name.to_string()))?;
@@ -734,16 +1486,16 @@ impl IndexWriter {
     pub fn flush(&mut self) -> io::Result<()> {
         self.flush_post()?;
         
-        self.main_buf.write_string("csearch index 2\n")?;
+        self.main_buf.write_header()?;
         
-        let roots_off = self.main_buf.offset();
+        let roots_off = self.main_buf.start_section();
         
         // Write roots
         let mut root_state = PathWriterState::new(NAME_GROUP_SIZE);
         
         // Since we can't easily sort self.roots inside the borrow of main_buf (which is in self),
         // we should sort before creating PathWriter.
-        self.roots.sort();
+        self.roots.sort_by(|a, b| cmp_paths(a, b));
         
         {
             let mut pw = PathWriter::new(&mut self.main_buf, None, &mut root_state);
@@ -755,14 +1507,15 @@ impl IndexWriter {
         let roots_count = self.roots.len(); 
         self.main_buf.align(16)?;
         
-        let name_off = self.main_buf.offset();
-        let mut name_f = self.name_buf.take().unwrap().finish()?;
-        let n = io::copy(&mut name_f, &mut self.main_buf.writer)?; 
-        self.main_buf.offset += n;
+        let name_off = self.main_buf.start_section();
+        let name_f = self.name_buf.take().unwrap().finish()?;
+        // Kept mapped to build the name postings from
+        let names = unsafe { Mmap::map(&name_f)? };
+        self.main_buf.copy_from(name_f)?;
         let name_count = self.num_name;
         self.main_buf.align(16)?;
         
-        let post_off = self.main_buf.offset();
+        let post_off = self.main_buf.start_section();
         self.merge_post()?;
         if self.verbose {
             println!("DEBUG: merge_post finished with num_trigram={}", self.num_trigram);
@@ -770,28 +1523,48 @@ impl IndexWriter {
         let trigram_count = self.num_trigram;
         self.main_buf.align(16)?;
         
-        let name_idx_off = self.main_buf.offset();
-        let mut name_idx_f = self.name_index_buf.take().unwrap().finish()?;
-        let n = io::copy(&mut name_idx_f, &mut self.main_buf.writer)?;
-        self.main_buf.offset += n;
+        let name_idx_off = self.main_buf.start_section();
+        let name_idx_f = self.name_index_buf.take().unwrap().finish()?;
+        self.main_buf.copy_from(name_idx_f)?;
         self.main_buf.align(16)?;
         
-        let post_idx_off = self.main_buf.offset();
-        let mut post_idx_f = self.post_index_buf.take().unwrap().finish()?;
-        let n = io::copy(&mut post_idx_f, &mut self.main_buf.writer)?;
-        self.main_buf.offset += n;
+        let post_idx_off = self.main_buf.start_section();
+        let post_idx_f = self.post_index_buf.take().unwrap().finish()?;
+        self.main_buf.copy_from(post_idx_f)?;
+        self.main_buf.align(16)?;
+        
+        let meta_off = self.main_buf.start_section();
+        let meta_f = self.meta_buf.take().unwrap().finish()?;
+        self.main_buf.copy_from(meta_f)?;
+        
+        let name_post_off = self.main_buf.start_section();
+        let mut name_post_index = IndexBuffer::new("")?;
+        let name_trigram_count = write_name_postings(&names, name_count, &mut self.main_buf, &mut name_post_index)?;
+        self.main_buf.align(16)?;
+        
+        let name_post_idx_off = self.main_buf.start_section();
+        self.main_buf.copy_from(name_post_index.finish()?)?;
         
-        self.main_buf.write_uint64(roots_off)?;
-        self.main_buf.write_uint64(roots_count as u64)?;
-        self.main_buf.write_uint64(name_off)?;
-        self.main_buf.write_uint64(name_count as u64)?;
-        self.main_buf.write_uint64(post_off)?;
-        self.main_buf.write_uint64(trigram_count as u64)?;
-        self.main_buf.write_uint64(name_idx_off)?;
-        self.main_buf.write_uint64(post_idx_off)?;
-        self.main_buf.write_string("\ncsearch trlr 2\n")?;
+        self.main_buf.write_trailer(&[
+            roots_off,
+            roots_count as u64,
+            name_off,
+            name_count as u64,
+            post_off,
+            trigram_count as u64,
+            name_idx_off,
+            post_idx_off,
+            meta_off,
+            name_post_off,
+            name_trigram_count as u64,
+            name_post_idx_off,
+        ])?;
         
-        self.main_buf.flush()?;
+        self.main_buf.publish()?;
+        
+        if let Some(files) = self.checkpoint.take() {
+            files.remove();
+        }
         
         Ok(())
     }
@@ -882,5 +1655,139 @@ impl IndexWriter {
     }
 }
 
+/// Writes an index at `file` with `roots` and `files`, given in index path
+/// order as names and contents, without reading anything from disk.
+#[cfg(test)]
+pub(crate) fn write_test_index(file: &str, roots: &[&str], files: &[(&str, &str)]) -> io::Result<()> {
+    let mut ix = IndexWriter::create(file)?;
+    for root in roots {
+        ix.add_root(root);
+    }
+    for (name, content) in files {
+        let mut trigrams: Vec<u32> = content.as_bytes().windows(3)
+            .map(|w| ((w[0] as u32) << 16) | ((w[1] as u32) << 8) | w[2] as u32)
+            .collect();
+        trigrams.sort_unstable();
+        trigrams.dedup();
+        let meta = FileMeta { size: content.len() as u64, mtime: 0, hash: content_hash(content.as_bytes()) };
+        ix.add_scanned(ScannedFile { name: name.to_string(), meta, trigrams, skipped: false })?;
+    }
+    ix.flush()
+}
 
-
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Writes `n` files with overlapping contents to `dir` and returns
+    /// their names in index path order.
+    fn write_files(dir: &Path, n: usize) -> Vec<String> {
+        let mut names: Vec<String> = (0..n).map(|i| {
+            let name = dir.join(format!("f{:04}.txt", i)).to_string_lossy().into_owned();
+            fs::write(&name, format!("file {} of {}\nshared line\n{}\n", i, n, "x".repeat(i % 50))).unwrap();
+            name
+        }).collect();
+        names.sort_by(|a, b| cmp_paths(a, b));
+        names
+    }
+    
+    fn build(file: &str, root: &str, names: &[String], threads: usize) -> Vec<u8> {
+        let mut ix = IndexWriter::create(file).unwrap();
+        ix.add_root(root);
+        ix.add_files(names, threads, |_, _| -> io::Result<()> { Ok(()) }).unwrap();
+        ix.flush().unwrap();
+        fs::read(file).unwrap()
+    }
+    
+    #[test]
+    fn add_files_is_the_same_on_any_number_of_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        let names = write_files(&src, 300);
+        let root = src.to_string_lossy();
+        let one = build(&dir.path().join("ix1").to_string_lossy(), &root, &names, 1);
+        let four = build(&dir.path().join("ix4").to_string_lossy(), &root, &names, 4);
+        assert!(one == four, "indexes built on 1 and 4 threads differ");
+    }
+    
+    #[test]
+    fn add_files_stops_when_the_callback_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = write_files(dir.path(), 200);
+        let mut ix = IndexWriter::create(&dir.path().join("ix").to_string_lossy()).unwrap();
+        let mut n = 0;
+        let result = ix.add_files(&names, 4, |_, _| {
+            n += 1;
+            if n == 3 { Err(io::Error::other("callback failed")) } else { Ok(()) }
+        });
+        assert!(result.is_err());
+        assert_eq!(n, 3);
+    }
+    
+    /// Adds `names` to a resumed writer and returns the index it writes.
+    fn finish_resumed(mut ix: IndexWriter, names: &[String]) -> Vec<u8> {
+        ix.add_files(names, 1, |_, _| -> io::Result<()> { Ok(()) }).unwrap();
+        ix.flush().unwrap();
+        fs::read(ix.file()).unwrap()
+    }
+    
+    #[test]
+    fn resumed_index_is_the_same_as_uninterrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        let names = write_files(&src, 200);
+        let root = src.to_string_lossy();
+        let whole = build(&dir.path().join("whole").to_string_lossy(), &root, &names, 1);
+        
+        let file = dir.path().join("ix").to_string_lossy().into_owned();
+        let sidecar = format!("{}.checkpoint", file);
+        let (first, rest) = names.split_at(names.len() / 2);
+        let mut ix = IndexWriter::create_checkpointed(&file, &sidecar).unwrap();
+        ix.add_root(&root);
+        ix.add_files(first, 1, |_, _| -> io::Result<()> { Ok(()) }).unwrap();
+        ix.checkpoint().unwrap();
+        drop(ix);
+        
+        let mut ix = IndexWriter::resume(&sidecar).unwrap();
+        assert_eq!(ix.indexed_names().unwrap(), first);
+        assert_eq!(ix.last_name(), first.last().unwrap());
+        ix.add_root(&root);
+        assert!(finish_resumed(ix, rest) == whole, "resumed index differs");
+        IndexWriter::remove_checkpoint(&sidecar);
+    }
+    
+    #[test]
+    fn restarted_index_is_the_same_as_uninterrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        let mut names = write_files(&src, 100);
+        let root = src.to_string_lossy();
+        
+        let file = dir.path().join("ix").to_string_lossy().into_owned();
+        let sidecar = format!("{}.checkpoint", file);
+        let mut ix = IndexWriter::create_checkpointed(&file, &sidecar).unwrap();
+        ix.add_root(&root);
+        ix.add_files(&names[..50], 1, |_, _| -> io::Result<()> { Ok(()) }).unwrap();
+        ix.checkpoint().unwrap();
+        drop(ix);
+        
+        // A file created since the checkpoint sorts before its last name,
+        // so the resumed run starts over
+        let early = src.join("a.txt").to_string_lossy().into_owned();
+        fs::write(&early, "added later\n").unwrap();
+        names.insert(0, early.clone());
+        let mut ix = IndexWriter::resume(&sidecar).unwrap();
+        assert!(cmp_paths(&early, ix.last_name()).is_le());
+        ix.restart().unwrap();
+        assert_eq!(ix.num_files(), 0);
+        assert_eq!(ix.last_name(), "");
+        let restarted = finish_resumed(ix, &names);
+        
+        let whole = build(&dir.path().join("whole").to_string_lossy(), &root, &names, 1);
+        assert!(restarted == whole, "restarted index differs");
+        IndexWriter::remove_checkpoint(&sidecar);
+    }
+}
@@ -1,5 +1,11 @@
+// Readers expose `next` methods in the style of the original Go port
+// rather than implementing `Iterator`.
+#![allow(clippy::should_implement_trait)]
+
 pub mod sparse_set;
 pub mod index;
+pub mod search;
+pub mod server;
 
 use std::path::Path;
 use std::env;
@@ -7,7 +13,8 @@ use std::env;
 /// Find the index file using the following priority:
 /// 1. Look for .csearchindex in current directory
 /// 2. Walk up directory tree looking for .csearchindex
-/// 3. Check CSEARCHINDEX environment variable
+/// 3. Check CSEARCHINDEX environment variable, the first index it lists
+///    that exists
 /// 4. Check HOME/.csearchindex
 /// 5. For cindex: create in current directory, for csearch: return error
 pub fn find_index_file(create_if_missing: bool) -> anyhow::Result<String> {
@@ -29,10 +36,8 @@ pub fn find_index_file(create_if_missing: bool) -> anyhow::Result<String> {
     }
     
     // 3. Check CSEARCHINDEX environment variable
-    if let Ok(env_path) = env::var("CSEARCHINDEX") {
-        if Path::new(&env_path).exists() {
-            return Ok(env_path);
-        }
+    if let Some(env_path) = env_indexes().into_iter().find(|p| Path::new(p).exists()) {
+        return Ok(env_path);
     }
     
     // 4. Check HOME/.csearchindex
@@ -50,7 +55,7 @@ pub fn find_index_file(create_if_missing: bool) -> anyhow::Result<String> {
         Ok(current_index.to_string_lossy().to_string())
     } else {
         // For csearch: try environment variable or HOME as fallback
-        if let Ok(env_path) = env::var("CSEARCHINDEX") {
+        if let Some(env_path) = env_indexes().into_iter().next() {
             return Ok(env_path);
         }
         
@@ -61,3 +66,49 @@ pub fn find_index_file(create_if_missing: bool) -> anyhow::Result<String> {
         anyhow::bail!("No index file found. Run cindex to create one.")
     }
 }
+
+/// Find the indexes csearch searches: the one `find_index_file` finds, or
+/// all of those listed in CSEARCHINDEX when it finds one of them.
+pub fn find_index_files() -> anyhow::Result<Vec<String>> {
+    let index = find_index_file(false)?;
+    let env_indexes = env_indexes();
+    if env_indexes.contains(&index) {
+        return Ok(env_indexes);
+    }
+    Ok(vec![index])
+}
+
+/// Find every .csearchindex from the current directory up, deepest
+/// first, for searching them stacked. Without any, falls back to
+/// `find_index_files`.
+pub fn find_stacked_index_files() -> anyhow::Result<Vec<String>> {
+    let current_dir = env::current_dir()?;
+    let indexes: Vec<String> = current_dir.ancestors()
+        .map(|dir| dir.join(".csearchindex"))
+        .filter(|index_path| index_path.exists())
+        .map(|index_path| index_path.to_string_lossy().to_string())
+        .collect();
+    if indexes.is_empty() {
+        return find_index_files();
+    }
+    Ok(indexes)
+}
+
+/// The delta index of `index_file`, which holds the changes `cindex --watch`
+/// found and has not folded into the index yet. Its files replace those of
+/// the index under its roots.
+pub fn delta_index_file(index_file: &str) -> String {
+    format!("{}.delta", index_file)
+}
+
+/// The indexes listed in CSEARCHINDEX, separated by colons (semicolons on
+/// Windows).
+fn env_indexes() -> Vec<String> {
+    match env::var_os("CSEARCHINDEX") {
+        Some(value) => env::split_paths(&value)
+            .filter(|p| !p.as_os_str().is_empty())
+            .map(|p| p.to_string_lossy().to_string())
+            .collect(),
+        None => Vec::new(),
+    }
+}
@@ -0,0 +1,555 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use regex::bytes::{Regex, RegexBuilder};
+use crate::index::Index;
+use crate::index::explain::Explanation;
+use crate::index::postings::PostingIterator;
+use crate::index::regexp::{analyze_regexp, Query};
+
+// Files a search thread may be given ahead of the results printed, which
+// bounds the results held in memory
+const SEARCH_WINDOW_PER_THREAD: usize = 16;
+
+/// Options of a `Searcher`.
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    pub ignore_case: bool,
+    /// Directory that relative names in the index are resolved against,
+    /// usually the one holding the index.
+    pub base_dir: PathBuf,
+    /// Lines of context to report before each match.
+    pub before_context: usize,
+    /// Lines of context to report after each match.
+    pub after_context: usize,
+    /// Match the pattern against whole files rather than line by line, so
+    /// that matches can span lines. `^` and `$` still match at the start
+    /// and end of every line.
+    pub multiline: bool,
+    /// Stop reading a file after this many matching lines, or matches in
+    /// multiline searches. The context after the last one is still
+    /// reported.
+    pub max_count: Option<u64>,
+}
+
+/// A line that matches the pattern of a search or, when context is
+/// asked for, a line around one. In multiline searches a match can span
+/// several lines, and matches that share a line are reported together.
+#[derive(Debug, Clone)]
+pub struct Match {
+    pub path: PathBuf,
+    /// Number of the line, from 1.
+    pub line_number: u64,
+    /// Number of the last line of a match that spans lines, otherwise
+    /// `line_number`.
+    pub last_line_number: u64,
+    /// Offset of the start of the line in the file.
+    pub byte_offset: u64,
+    /// The line, or all the lines of the match, with the final `\n` if
+    /// there is one.
+    pub line: Vec<u8>,
+    /// Byte ranges of each match of the pattern within `line`.
+    pub submatches: Vec<Range<usize>>,
+    /// Whether this is a context line, which has no submatches.
+    pub context: bool,
+}
+
+impl Match {
+    /// Splits a match that spans lines into one per line, each with the
+    /// parts of the submatches that fall on it.
+    pub fn lines(&self) -> Vec<Match> {
+        if self.last_line_number == self.line_number {
+            return vec![self.clone()];
+        }
+        let mut lines = Vec::new();
+        let mut start = 0;
+        for (i, line) in self.line.split_inclusive(|&b| b == b'\n').enumerate() {
+            let end = start + line.len();
+            let submatches = self.submatches.iter()
+                .filter(|r| r.start < end && (r.end > start || (r.is_empty() && r.start == start)))
+                .map(|r| r.start.max(start) - start..r.end.min(end) - start)
+                .collect();
+            lines.push(Match {
+                path: self.path.clone(),
+                line_number: self.line_number + i as u64,
+                last_line_number: self.line_number + i as u64,
+                byte_offset: self.byte_offset + start as u64,
+                line: line.to_vec(),
+                submatches,
+                context: self.context,
+            });
+            start = end;
+        }
+        lines
+    }
+}
+
+/// The lines found in one file by `Searcher::search_file`.
+#[derive(Debug)]
+pub struct FileResult {
+    pub path: PathBuf,
+    pub lines: Vec<Match>,
+    pub bytes_read: u64,
+    pub elapsed: Duration,
+    /// Why the file could not be opened or read to the end. The lines
+    /// found before a read error are kept.
+    pub error: Option<io::Error>,
+}
+
+/// Searches the files of an index for a regular expression: the trigrams
+/// of the pattern pick the candidate files, which are then read and
+/// matched line by line.
+pub struct Searcher<'a> {
+    index: &'a Index,
+    query: Query,
+    re: Regex,
+    options: SearchOptions,
+}
+
+impl<'a> Searcher<'a> {
+    pub fn new(index: &'a Index, pattern: &str, options: SearchOptions) -> io::Result<Self> {
+        let pattern = if options.ignore_case { format!("(?i){}", pattern) } else { pattern.to_string() };
+        let query = analyze_regexp(&pattern)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let re = RegexBuilder::new(&pattern)
+            .multi_line(options.multiline)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Searcher { index, query, re, options })
+    }
+    
+    pub fn index(&self) -> &'a Index {
+        self.index
+    }
+    
+    /// The trigram query the candidates are selected with.
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+    
+    pub fn regex(&self) -> &Regex {
+        &self.re
+    }
+    
+    /// Explains how the candidates are selected, only among `restrict`
+    /// when given, which must be sorted.
+    pub fn explain(&self, restrict: Option<Vec<u32>>) -> io::Result<Explanation> {
+        self.index.explain(self.re.as_str(), restrict)
+    }
+    
+    /// Returns the files that may match, only among `restrict` when
+    /// given, which must be sorted.
+    pub fn candidates(&self, restrict: Option<Vec<u32>>) -> Box<dyn PostingIterator + 'a> {
+        self.index.posting_iter(&self.query, restrict)
+    }
+    
+    /// The path of file `fileid` of the index.
+    pub fn path(&self, fileid: u32) -> PathBuf {
+        resolve_path(&self.options.base_dir, &self.index.name(fileid as usize))
+    }
+    
+    /// Opens `path` and returns its matching lines.
+    pub fn search_path(&self, path: &Path) -> io::Result<Matches<'_, BufReader<File>>> {
+        let file = File::open(path)?;
+        Ok(self.search_reader(path, BufReader::new(file)))
+    }
+    
+    /// Returns the matching lines of `reader`, reported as lines of `path`.
+    pub fn search_reader<R: BufRead>(&self, path: &Path, reader: R) -> Matches<'_, R> {
+        Matches {
+            re: &self.re,
+            path: path.to_path_buf(),
+            reader,
+            line_number: 0,
+            offset: 0,
+            buf: Vec::new(),
+            before_context: self.options.before_context,
+            after_context: self.options.after_context,
+            before: VecDeque::new(),
+            after_left: 0,
+            queue: VecDeque::new(),
+            multiline: self.options.multiline,
+            max_count: self.options.max_count,
+            matched: 0,
+        }
+    }
+    
+    /// Searches all of `path` at once.
+    pub fn search_file(&self, path: PathBuf) -> FileResult {
+        let started = Instant::now();
+        let mut result = FileResult { path, lines: Vec::new(), bytes_read: 0, elapsed: Duration::ZERO, error: None };
+        match self.search_path(&result.path) {
+            Ok(mut matches) => {
+                loop {
+                    match matches.next() {
+                        Ok(Some(m)) => result.lines.push(m),
+                        Ok(None) => break,
+                        Err(e) => {
+                            result.error = Some(e);
+                            break;
+                        }
+                    }
+                }
+                result.bytes_read = matches.bytes_read();
+            }
+            Err(e) => result.error = Some(e),
+        }
+        result.elapsed = started.elapsed();
+        result
+    }
+    
+    /// Searches `files` on `threads` threads. `found` runs on the calling
+    /// thread for each file, in the order of `files`, and returns false to
+    /// stop the search. Files are taken from `files` only as threads become
+    /// free, so stopping early leaves the rest unread.
+    pub fn search_parallel<I, F>(&self, files: I, threads: usize, mut found: F) -> io::Result<()>
+    where
+        I: Iterator<Item = PathBuf>,
+        F: FnMut(FileResult) -> io::Result<bool>,
+    {
+        let mut files = files;
+        if threads <= 1 {
+            for path in files {
+                if !found(self.search_file(path))? {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+        
+        let window = threads * SEARCH_WINDOW_PER_THREAD;
+        let stop = AtomicBool::new(false);
+        let (job_tx, job_rx) = mpsc::channel::<(usize, PathBuf)>();
+        let job_rx = Mutex::new(job_rx);
+        thread::scope(|scope| {
+            let (result_tx, result_rx) = mpsc::channel();
+            for _ in 0..threads {
+                let (job_rx, result_tx, stop) = (&job_rx, result_tx.clone(), &stop);
+                scope.spawn(move || {
+                    loop {
+                        let job = job_rx.lock().unwrap().recv();
+                        let Ok((i, path)) = job else {
+                            break;
+                        };
+                        if stop.load(Ordering::Relaxed) || result_tx.send((i, self.search_file(path))).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(result_tx);
+            
+            let result = self.collect_in_order(&mut files, window, &job_tx, &result_rx, &mut found);
+            stop.store(true, Ordering::Relaxed);
+            drop(job_tx);
+            result
+        })
+    }
+    
+    fn collect_in_order<I, F>(
+        &self,
+        files: &mut I,
+        window: usize,
+        jobs: &mpsc::Sender<(usize, PathBuf)>,
+        results: &mpsc::Receiver<(usize, FileResult)>,
+        found: &mut F,
+    ) -> io::Result<()>
+    where
+        I: Iterator<Item = PathBuf>,
+        F: FnMut(FileResult) -> io::Result<bool>,
+    {
+        let mut pending = HashMap::new();
+        let (mut sent, mut done) = (0, 0);
+        let mut more = true;
+        loop {
+            while more && sent < done + window {
+                match files.next() {
+                    Some(path) => {
+                        jobs.send((sent, path)).map_err(|_| io::Error::other("search threads exited early"))?;
+                        sent += 1;
+                    }
+                    None => more = false,
+                }
+            }
+            if done == sent {
+                return Ok(());
+            }
+            let result = loop {
+                if let Some(r) = pending.remove(&done) {
+                    break r;
+                }
+                match results.recv() {
+                    Ok((i, r)) => { pending.insert(i, r); }
+                    Err(_) => return Err(io::Error::other("search threads exited early")),
+                }
+            };
+            done += 1;
+            if !found(result)? {
+                return Ok(());
+            }
+        }
+    }
+    
+    /// Searches every candidate, only among `restrict` when given.
+    pub fn search(&self, restrict: Option<Vec<u32>>) -> SearchResults<'_, 'a> {
+        SearchResults {
+            searcher: self,
+            candidates: self.candidates(restrict),
+            current: None,
+        }
+    }
+}
+
+/// The matching lines of one file, with their context lines. Context
+/// shared by nearby matches is reported once.
+pub struct Matches<'s, R> {
+    re: &'s Regex,
+    path: PathBuf,
+    reader: R,
+    line_number: u64,
+    offset: u64,
+    buf: Vec<u8>,
+    before_context: usize,
+    after_context: usize,
+    // The last lines read, which precede the next match
+    before: VecDeque<Match>,
+    // Lines of context still to report after the last match
+    after_left: usize,
+    // Lines ready to be returned
+    queue: VecDeque<Match>,
+    // Set in multiline mode until the whole file has been searched
+    multiline: bool,
+    max_count: Option<u64>,
+    // Matching lines found so far
+    matched: u64,
+}
+
+impl<R: BufRead> Matches<'_, R> {
+    /// Number of bytes of the file read so far.
+    pub fn bytes_read(&self) -> u64 {
+        self.offset
+    }
+    
+    /// Reads up to the next matching or context line. Returns None at the
+    /// end of the file.
+    pub fn next(&mut self) -> io::Result<Option<Match>> {
+        if self.multiline {
+            self.search_whole()?;
+            self.multiline = false;
+        }
+        loop {
+            if let Some(line) = self.queue.pop_front() {
+                return Ok(Some(line));
+            }
+            let limited = self.max_count.is_some_and(|max| self.matched >= max);
+            if limited && self.after_left == 0 {
+                return Ok(None);
+            }
+            self.buf.clear();
+            let n = self.reader.read_until(b'\n', &mut self.buf)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.line_number += 1;
+            let start = self.offset;
+            self.offset += n as u64;
+            let line = self.buf.strip_suffix(b"\n").unwrap_or(&self.buf);
+            // Past the limit, matching lines are only reported as context
+            let submatches: Vec<Range<usize>> = if limited {
+                Vec::new()
+            } else {
+                self.re.find_iter(line).map(|m| m.range()).collect()
+            };
+            let matched = !submatches.is_empty();
+            if !matched && self.after_left == 0 && self.before_context == 0 {
+                continue;
+            }
+            let line = Match {
+                path: self.path.clone(),
+                line_number: self.line_number,
+                last_line_number: self.line_number,
+                byte_offset: start,
+                line: self.buf.clone(),
+                submatches,
+                context: !matched,
+            };
+            if matched {
+                self.matched += 1;
+                self.queue.extend(self.before.drain(..));
+                self.queue.push_back(line);
+                self.after_left = self.after_context;
+            } else if self.after_left > 0 {
+                self.after_left -= 1;
+                self.queue.push_back(line);
+            } else {
+                self.before.push_back(line);
+                if self.before.len() > self.before_context {
+                    self.before.pop_front();
+                }
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Matches<'_, R> {
+    /// Reads the whole file, matches the pattern against it and queues the
+    /// lines of every match with their context.
+    fn search_whole(&mut self) -> io::Result<()> {
+        let mut data = Vec::new();
+        self.reader.read_to_end(&mut data)?;
+        self.offset = data.len() as u64;
+        
+        let mut line_starts = vec![0];
+        line_starts.extend(data.iter().enumerate().filter(|&(_, &b)| b == b'\n').map(|(i, _)| i + 1));
+        // A final newline doesn't start another line
+        if line_starts.last() == Some(&data.len()) {
+            line_starts.pop();
+        }
+        let line_of = |pos: usize| line_starts.partition_point(|&s| s <= pos) - 1;
+        
+        // Matches that share a line form one group of lines
+        let mut groups: Vec<(usize, usize, Vec<Range<usize>>)> = Vec::new();
+        for m in self.re.find_iter(&data) {
+            if data.is_empty() {
+                break;
+            }
+            // An empty match after the final newline is on no line
+            if m.start() == data.len() && data.ends_with(b"\n") {
+                continue;
+            }
+            let first = line_of(m.start());
+            let last = if m.end() > m.start() { line_of(m.end() - 1) } else { first };
+            match groups.last_mut() {
+                Some(group) if group.1 >= first => {
+                    group.1 = group.1.max(last);
+                    group.2.push(m.range());
+                }
+                _ => groups.push((first, last, vec![m.range()])),
+            }
+        }
+        if let Some(max) = self.max_count {
+            groups.truncate(max.try_into().unwrap_or(usize::MAX));
+        }
+        self.matched = groups.len() as u64;
+        
+        let mut next_line = 0;
+        for (i, (first, last, ranges)) in groups.iter().enumerate() {
+            let context_start = first.saturating_sub(self.before_context).max(next_line);
+            for line in context_start..*first {
+                self.queue.push_back(self.whole_line(&data, &line_starts, line..line, Vec::new()));
+            }
+            let start = line_starts[*first];
+            let submatches = ranges.iter().map(|r| r.start - start..r.end - start).collect();
+            self.queue.push_back(self.whole_line(&data, &line_starts, *first..*last, submatches));
+            // After context stops where the next group's lines begin
+            let limit = groups.get(i + 1).map_or(line_starts.len(), |g| g.0);
+            let context_end = (last + 1 + self.after_context).min(limit);
+            for line in last + 1..context_end {
+                self.queue.push_back(self.whole_line(&data, &line_starts, line..line, Vec::new()));
+            }
+            next_line = context_end.max(last + 1);
+        }
+        Ok(())
+    }
+    
+    /// The lines `lines.start` to `lines.end` inclusive, counted from 0.
+    fn whole_line(&self, data: &[u8], line_starts: &[usize], lines: Range<usize>, submatches: Vec<Range<usize>>) -> Match {
+        let start = line_starts[lines.start];
+        let end = line_starts.get(lines.end + 1).copied().unwrap_or(data.len());
+        Match {
+            path: self.path.clone(),
+            line_number: lines.start as u64 + 1,
+            last_line_number: lines.end as u64 + 1,
+            byte_offset: start as u64,
+            line: data[start..end].to_vec(),
+            context: submatches.is_empty(),
+            submatches,
+        }
+    }
+}
+
+/// The matching lines of all candidate files, in file id order.
+pub struct SearchResults<'s, 'a> {
+    searcher: &'s Searcher<'a>,
+    candidates: Box<dyn PostingIterator + 'a>,
+    current: Option<Matches<'s, BufReader<File>>>,
+}
+
+impl SearchResults<'_, '_> {
+    /// Returns the next matching line. A file that cannot be read is
+    /// reported as an error naming it, and the search goes on with the
+    /// next one.
+    pub fn next(&mut self) -> Option<io::Result<Match>> {
+        loop {
+            if let Some(matches) = &mut self.current {
+                match matches.next() {
+                    Ok(Some(m)) => return Some(Ok(m)),
+                    Ok(None) => self.current = None,
+                    Err(e) => {
+                        let err = path_error(&matches.path, e);
+                        self.current = None;
+                        return Some(Err(err));
+                    }
+                }
+            }
+            let fileid = self.candidates.next()?;
+            let path = self.searcher.path(fileid);
+            match self.searcher.search_path(&path) {
+                Ok(matches) => self.current = Some(matches),
+                Err(e) => return Some(Err(path_error(&path, e))),
+            }
+        }
+    }
+}
+
+fn path_error(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+/// Returns the ids of the indexed files whose name matches `pattern`,
+/// narrowed down with the trigrams of the names before decoding any.
+pub fn find_files(index: &Index, pattern: &str, ignore_case: bool) -> io::Result<Vec<u32>> {
+    let pattern = if ignore_case { format!("(?i){}", pattern) } else { pattern.to_string() };
+    let q = analyze_regexp(&pattern)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let re = regex::Regex::new(&pattern)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    
+    let candidates = index.name_query(&q);
+    // Decode all names in one pass when the trigrams don't narrow them down
+    if candidates.len() == index.num_name {
+        let mut matches = Vec::new();
+        let mut r = index.names_at(0, index.num_name);
+        let mut id = 0;
+        while let Some(name) = r.next() {
+            if re.is_match(&name) {
+                matches.push(id);
+            }
+            id += 1;
+        }
+        return Ok(matches);
+    }
+    Ok(candidates.into_iter()
+        .filter(|&id| re.is_match(&index.name(id as usize)))
+        .collect())
+}
+
+/// Resolves a name stored in an index: relative names are relative to
+/// `base_dir`.
+pub fn resolve_path(base_dir: &Path, name: &str) -> PathBuf {
+    let raw_path = Path::new(name);
+    if raw_path.is_relative() {
+        base_dir.join(raw_path)
+            .components()
+            .filter(|c| !matches!(c, Component::CurDir))
+            .collect()
+    } else {
+        raw_path.to_path_buf()
+    }
+}
@@ -0,0 +1,552 @@
+use std::cell::Cell;
+use std::fs;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ignore::types::{Types, TypesBuilder};
+use serde_json::{json, Map, Value};
+use tiny_http::{Header, Method, Request, Response};
+use crate::index::Index;
+use crate::search::{find_files, resolve_path, Match, SearchOptions, Searcher};
+
+// Largest request body accepted
+const MAX_BODY: u64 = 1 << 20;
+
+/// Where `Server::run` listens.
+#[derive(Debug, Clone)]
+pub enum Listen {
+    /// A TCP address, which must be a loopback one: the server reads any
+    /// indexed file for whoever asks.
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Identifies one generation of an index file. Publishing a new index
+/// renames a new file over the old one, which changes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+    #[cfg(unix)]
+    inode: (u64, u64),
+}
+
+impl Stamp {
+    fn of(path: &Path) -> io::Result<Self> {
+        let meta = fs::metadata(path)?;
+        #[cfg(unix)]
+        let inode = {
+            use std::os::unix::fs::MetadataExt;
+            (meta.dev(), meta.ino())
+        };
+        Ok(Stamp {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+            #[cfg(unix)]
+            inode,
+        })
+    }
+}
+
+/// An open generation of an index.
+struct Loaded {
+    index: Index,
+    stamp: Stamp,
+    generation: u64,
+    loaded_at: SystemTime,
+}
+
+/// An index file kept open, reopened when a new generation is published.
+struct IndexSlot {
+    path: PathBuf,
+    base_dir: PathBuf,
+    current: RwLock<Arc<Loaded>>,
+}
+
+impl IndexSlot {
+    fn open(path: &Path) -> io::Result<Self> {
+        let stamp = Stamp::of(path)?;
+        let index = Index::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        let loaded = Loaded { index, stamp, generation: 1, loaded_at: SystemTime::now() };
+        Ok(IndexSlot {
+            path: path.to_path_buf(),
+            base_dir: path.parent().unwrap_or(Path::new(".")).to_path_buf(),
+            current: RwLock::new(Arc::new(loaded)),
+        })
+    }
+    
+    /// The latest generation of the index. Requests still using an older
+    /// one keep it open until they finish.
+    fn get(&self) -> Arc<Loaded> {
+        let current = self.current.read().unwrap().clone();
+        let stamp = match Stamp::of(&self.path) {
+            Ok(stamp) if stamp != current.stamp => stamp,
+            // Also keep serving the last generation if the file is gone
+            _ => return current,
+        };
+        let mut slot = self.current.write().unwrap();
+        if slot.stamp == stamp {
+            return slot.clone();
+        }
+        match Index::open(&self.path) {
+            Ok(index) => {
+                let generation = slot.generation + 1;
+                *slot = Arc::new(Loaded { index, stamp, generation, loaded_at: SystemTime::now() });
+                eprintln!("Loaded generation {} of {}", generation, self.path.display());
+            }
+            Err(e) => eprintln!("Keeping generation {} of {}: {}", slot.generation, self.path.display(), e),
+        }
+        slot.clone()
+    }
+}
+
+/// A request that cannot be answered, with the HTTP status to answer it
+/// with.
+#[derive(Debug)]
+struct RequestError {
+    status: u16,
+    message: String,
+}
+
+impl RequestError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        RequestError { status, message: message.into() }
+    }
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> Self {
+        let status = match e.kind() {
+            io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => 400,
+            io::ErrorKind::NotFound => 404,
+            _ => 500,
+        };
+        RequestError::new(status, e.to_string())
+    }
+}
+
+/// The parameters of a request, from its JSON body.
+struct Params(Map<String, Value>);
+
+impl Params {
+    fn parse(body: &str) -> Result<Self, RequestError> {
+        if body.trim().is_empty() {
+            return Ok(Params(Map::new()));
+        }
+        match serde_json::from_str(body) {
+            Ok(Value::Object(map)) => Ok(Params(map)),
+            Ok(_) => Err(RequestError::new(400, "request body must be a JSON object")),
+            Err(e) => Err(RequestError::new(400, format!("invalid JSON: {}", e))),
+        }
+    }
+    
+    fn str(&self, key: &str) -> Result<Option<&str>, RequestError> {
+        match self.0.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(_) => Err(RequestError::new(400, format!("`{}` must be a string", key))),
+        }
+    }
+    
+    fn bool(&self, key: &str) -> Result<bool, RequestError> {
+        match self.0.get(key) {
+            None | Some(Value::Null) => Ok(false),
+            Some(Value::Bool(b)) => Ok(*b),
+            Some(_) => Err(RequestError::new(400, format!("`{}` must be a boolean", key))),
+        }
+    }
+    
+    fn u64(&self, key: &str) -> Result<Option<u64>, RequestError> {
+        match self.0.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(v) => v.as_u64().map(Some)
+                .ok_or_else(|| RequestError::new(400, format!("`{}` must be a non-negative integer", key))),
+        }
+    }
+}
+
+/// The files of an index a request is limited to.
+struct Filter {
+    restrict: Option<Vec<u32>>,
+    types: Option<Types>,
+}
+
+impl Filter {
+    fn new(index: &Index, params: &Params) -> Result<Self, RequestError> {
+        let ignore_case = params.bool("ignore_case")?;
+        let scope = match params.str("dir")? {
+            Some(dir) => {
+                let dir = Path::new(dir).canonicalize().or_else(|_| std::path::absolute(dir))?;
+                Some(index.name_range(&dir.to_string_lossy()))
+            }
+            None => None,
+        };
+        let restrict = match params.str("files")? {
+            Some(files) => {
+                let mut ids = find_files(index, files, ignore_case)?;
+                if let Some(ref scope) = scope {
+                    ids.retain(|&id| scope.contains(&(id as usize)));
+                }
+                Some(ids)
+            }
+            None => scope.map(|scope| (scope.start as u32..scope.end as u32).collect()),
+        };
+        let types = match params.str("file_type")? {
+            Some(ftype) => {
+                let mut builder = TypesBuilder::new();
+                builder.add_defaults();
+                builder.select(ftype);
+                Some(builder.build().map_err(|e| RequestError::new(400, e.to_string()))?)
+            }
+            None => None,
+        };
+        Ok(Filter { restrict, types })
+    }
+    
+    fn allows(&self, path: &Path) -> bool {
+        self.types.as_ref().is_none_or(|types| types.matched(path, false).is_whitelist())
+    }
+}
+
+/// Text as JSON: a string when it is valid UTF-8, and base64 otherwise.
+fn json_data(bytes: &[u8]) -> Value {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => json!({ "text": text }),
+        Err(_) => json!({ "bytes": BASE64.encode(bytes) }),
+    }
+}
+
+fn json_match(index_path: &Path, m: &Match) -> Value {
+    let submatches: Vec<Value> = m.submatches.iter()
+        .map(|r| json!({ "start": r.start, "end": r.end }))
+        .collect();
+    json!({
+        "index": index_path.to_string_lossy(),
+        "path": m.path.to_string_lossy(),
+        "line_number": m.line_number,
+        "byte_offset": m.byte_offset,
+        "lines": json_data(&m.line),
+        "submatches": submatches,
+        "context": m.context,
+    })
+}
+
+/// The `Host` values that name a server bound to `addr`.
+fn allowed_hosts(addr: SocketAddr) -> Vec<String> {
+    let mut hosts = vec![addr.to_string(), format!("localhost:{}", addr.port())];
+    if addr.port() == 80 {
+        hosts.extend([addr.ip().to_string(), "localhost".to_string()]);
+        if addr.is_ipv6() {
+            hosts.push(format!("[{}]", addr.ip()));
+        }
+    }
+    hosts
+}
+
+/// Rejects requests whose `Host`, or `Origin` when sent, is not one of
+/// `hosts`.
+fn check_host(request: &Request, hosts: &[String]) -> Result<(), RequestError> {
+    let header = |name: &'static str| {
+        request.headers().iter().find(|h| h.field.equiv(name)).map(|h| h.value.as_str())
+    };
+    let host = header("Host").unwrap_or("");
+    if !hosts.iter().any(|h| h.eq_ignore_ascii_case(host)) {
+        return Err(RequestError::new(403, format!("unexpected Host: {:?}", host)));
+    }
+    if let Some(origin) = header("Origin")
+        && !hosts.iter().any(|h| origin.eq_ignore_ascii_case(&format!("http://{}", h)))
+    {
+        return Err(RequestError::new(403, format!("unexpected Origin: {:?}", origin)));
+    }
+    Ok(())
+}
+
+fn unix_secs(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64())
+}
+
+/// Answers search, file list and stats requests over HTTP with JSON,
+/// keeping its indexes open between requests.
+pub struct Server {
+    indexes: Vec<IndexSlot>,
+    /// Threads answering requests.
+    pub workers: usize,
+    /// Threads searching files for each request.
+    pub search_threads: usize,
+    started: Instant,
+    requests: AtomicU64,
+}
+
+impl Server {
+    /// Opens `indexes`, which must all be valid.
+    pub fn new<P: AsRef<Path>>(indexes: &[P]) -> io::Result<Self> {
+        let indexes = indexes.iter().map(|p| IndexSlot::open(p.as_ref())).collect::<io::Result<_>>()?;
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        Ok(Server {
+            indexes,
+            workers: threads.max(2),
+            search_threads: threads,
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+        })
+    }
+    
+    /// Listens on `listen` and answers requests until the process ends.
+    pub fn run(&self, listen: &Listen) -> io::Result<()> {
+        let server = match listen {
+            Listen::Tcp(addr) => {
+                if !addr.ip().is_loopback() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        format!("{}: only loopback addresses can be served", addr)));
+                }
+                tiny_http::Server::http(addr)
+            }
+            #[cfg(unix)]
+            Listen::Unix(path) => {
+                // A socket left by a server that is gone would fail the bind,
+                // but one that still accepts connections is in use
+                use std::os::unix::fs::FileTypeExt;
+                use std::os::unix::net::UnixStream;
+                if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                    match UnixStream::connect(path) {
+                        Ok(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                            format!("{}: address in use by a running server", path.display()))),
+                        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+                        Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+                    }
+                }
+                tiny_http::Server::http_unix(path)
+            }
+        }.map_err(io::Error::other)?;
+        // A page on another site can reach a loopback server through a name
+        // of its own that resolves to it, so TCP requests must name the
+        // server itself
+        let hosts = server.server_addr().to_ip().map(allowed_hosts);
+        match listen {
+            Listen::Tcp(_) => eprintln!("Listening on http://{}", server.server_addr()),
+            #[cfg(unix)]
+            Listen::Unix(path) => eprintln!("Listening on {}", path.display()),
+        }
+        
+        thread::scope(|scope| {
+            for _ in 0..self.workers {
+                scope.spawn(|| {
+                    loop {
+                        match server.recv() {
+                            Ok(request) => self.handle(request, hosts.as_deref()),
+                            Err(e) => eprintln!("Failed to receive request: {}", e),
+                        }
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+    
+    fn handle(&self, mut request: Request, hosts: Option<&[String]>) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let routed = match hosts {
+            Some(hosts) => check_host(&request, hosts).and_then(|_| self.route(&mut request)),
+            None => self.route(&mut request),
+        };
+        let (status, body) = match routed {
+            Ok(body) => (200, body),
+            Err(e) => (e.status, json!({ "error": e.message })),
+        };
+        let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+        let response = Response::from_string(body.to_string()).with_status_code(status).with_header(header);
+        if let Err(e) = request.respond(response) {
+            eprintln!("Failed to answer request: {}", e);
+        }
+    }
+    
+    fn route(&self, request: &mut Request) -> Result<Value, RequestError> {
+        let path = request.url().split('?').next().unwrap_or("").to_string();
+        let method = request.method().clone();
+        match (path.as_str(), method) {
+            ("/stats", Method::Get) => Ok(self.stats()),
+            ("/search", Method::Post) => self.search(&Self::params(request)?),
+            ("/files", Method::Post) => self.files(&Self::params(request)?),
+            ("/stats" | "/search" | "/files", _) => Err(RequestError::new(405, "method not allowed")),
+            _ => Err(RequestError::new(404, format!("no such endpoint: {}", path))),
+        }
+    }
+    
+    fn params(request: &mut Request) -> Result<Params, RequestError> {
+        let mut body = String::new();
+        request.as_reader().take(MAX_BODY + 1).read_to_string(&mut body)
+            .map_err(|e| RequestError::new(400, format!("failed to read request: {}", e)))?;
+        if body.len() as u64 > MAX_BODY {
+            return Err(RequestError::new(413, "request body too large"));
+        }
+        Params::parse(&body)
+    }
+    
+    /// The indexes a request asks for with `index`, or all of them.
+    fn selected(&self, params: &Params) -> Result<Vec<&IndexSlot>, RequestError> {
+        match params.str("index")? {
+            Some(path) => self.indexes.iter()
+                .find(|slot| slot.path == Path::new(path))
+                .map(|slot| vec![slot])
+                .ok_or_else(|| RequestError::new(404, format!("index not served: {}", path))),
+            None => Ok(self.indexes.iter().collect()),
+        }
+    }
+    
+    /// Searches the files of the selected indexes for `pattern`. Matching
+    /// lines are counted against `max_results` across indexes.
+    fn search(&self, params: &Params) -> Result<Value, RequestError> {
+        let started = Instant::now();
+        let pattern = params.str("pattern")?.ok_or_else(|| RequestError::new(400, "missing `pattern`"))?;
+        let context = params.u64("context")?.unwrap_or(0);
+        let max_results = params.u64("max_results")?;
+        let mut matches = Vec::new();
+        let mut errors = Vec::new();
+        let (mut candidates, mut searched, mut matched_lines) = (0u64, 0u64, 0u64);
+        let mut truncated = false;
+        
+        let slots = self.selected(params)?;
+        for (i, slot) in slots.iter().enumerate() {
+            let loaded = slot.get();
+            let after_context = params.u64("after_context")?.unwrap_or(context) as usize;
+            let options = SearchOptions {
+                ignore_case: params.bool("ignore_case")?,
+                base_dir: slot.base_dir.clone(),
+                before_context: params.u64("before_context")?.unwrap_or(context) as usize,
+                after_context,
+                multiline: params.bool("multiline")?,
+                max_count: params.u64("max_count")?,
+            };
+            let searcher = Searcher::new(&loaded.index, pattern, options)?;
+            let filter = Filter::new(&loaded.index, params)?;
+            let mut post = searcher.candidates(filter.restrict.clone());
+            // Files handed to the search, which may run ahead of the results
+            let handed = Cell::new(0u64);
+            let mut paths = std::iter::from_fn(|| loop {
+                let fileid = post.next()?;
+                candidates += 1;
+                let path = searcher.path(fileid);
+                if filter.allows(&path) {
+                    handed.set(handed.get() + 1);
+                    return Some(path);
+                }
+            });
+            let mut searched_here = 0;
+            let mut reached = false;
+            searcher.search_parallel(paths.by_ref(), self.search_threads, |file| {
+                searched += 1;
+                searched_here += 1;
+                if let Some(e) = file.error {
+                    errors.push(json!({ "path": file.path.to_string_lossy(), "error": e.to_string() }));
+                }
+                // Once the limit is reached, only the context after the last
+                // match is kept
+                let mut trailing = None;
+                for m in &file.lines {
+                    if let Some(left) = trailing {
+                        if !m.context {
+                            truncated = true;
+                            break;
+                        }
+                        if left == 0 {
+                            continue;
+                        }
+                        trailing = Some(left - 1);
+                    } else if !m.context {
+                        matched_lines += 1;
+                        if max_results.is_some_and(|max| matched_lines >= max) {
+                            trailing = Some(after_context);
+                            reached = true;
+                        }
+                    }
+                    matches.push(json_match(&slot.path, m));
+                }
+                Ok(!reached)
+            })?;
+            if reached {
+                // Files not searched yet, here or in the other indexes, may
+                // hold more matches
+                truncated = truncated || handed.get() > searched_here || paths.next().is_some() || i + 1 < slots.len();
+                break;
+            }
+        }
+        
+        Ok(json!({
+            "matches": matches,
+            "errors": errors,
+            "truncated": truncated,
+            "stats": {
+                "candidates": candidates,
+                "searched": searched,
+                "matched_lines": matched_lines,
+                "elapsed": started.elapsed().as_secs_f64(),
+            },
+        }))
+    }
+    
+    /// Lists the files of the selected indexes, only those whose name
+    /// matches `files` when given.
+    fn files(&self, params: &Params) -> Result<Value, RequestError> {
+        let max_results = params.u64("max_results")?;
+        let mut files = Vec::new();
+        let mut truncated = false;
+        'indexes: for slot in self.selected(params)? {
+            let loaded = slot.get();
+            let filter = Filter::new(&loaded.index, params)?;
+            let ids: Box<dyn Iterator<Item = u32>> = match filter.restrict {
+                Some(ref ids) => Box::new(ids.iter().copied()),
+                None => Box::new(0..loaded.index.num_name as u32),
+            };
+            for fileid in ids {
+                let path = resolve_path(&slot.base_dir, &loaded.index.name(fileid as usize));
+                if !filter.allows(&path) {
+                    continue;
+                }
+                if max_results.is_some_and(|max| files.len() as u64 >= max) {
+                    truncated = true;
+                    break 'indexes;
+                }
+                files.push(Value::from(path.to_string_lossy()));
+            }
+        }
+        Ok(json!({ "files": files, "truncated": truncated }))
+    }
+    
+    /// The indexes served, with the generation of each, and the requests
+    /// answered so far.
+    fn stats(&self) -> Value {
+        let indexes: Vec<Value> = self.indexes.iter()
+            .map(|slot| {
+                let loaded = slot.get();
+                let ix = &loaded.index;
+                let mut roots = Vec::new();
+                let mut r = ix.roots();
+                while let Some(root) = r.next() {
+                    roots.push(root);
+                }
+                json!({
+                    "path": slot.path.to_string_lossy(),
+                    "generation": loaded.generation,
+                    "loaded_at": unix_secs(loaded.loaded_at),
+                    "version": ix.version,
+                    "bytes": ix.mmap.len(),
+                    "files": ix.num_name,
+                    "posting_lists": ix.num_post,
+                    "roots": roots,
+                })
+            })
+            .collect();
+        json!({
+            "uptime": self.started.elapsed().as_secs_f64(),
+            "requests": self.requests.load(Ordering::Relaxed),
+            "indexes": indexes,
+        })
+    }
+}